@@ -1,5 +1,8 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use keri_controller::{error::ControllerError, IdentifierPrefix};
 use keri_core::{actor::prelude::SelfAddressingIdentifier, keys::KeysError};
+use notifier::{NotificationPreferences, OutgoingWebhook, WebPushSubscription, DEFAULT_LABEL};
+use ring::rand::{SecureRandom, SystemRandom};
 use thiserror::Error;
 use url::Url;
 use validate::ExchangeArguments;
@@ -8,10 +11,12 @@ pub mod messagebox;
 pub mod messagebox_listener;
 pub mod notifier;
 pub mod oobis;
-mod responses_store;
+pub mod ratelimit;
+pub mod responses_store;
 pub mod storage;
 pub mod validate;
 pub mod verify;
+mod websocket;
 
 use crate::validate::MessageType;
 
@@ -41,14 +46,124 @@ pub enum MessageboxError {
     OobiError(ControllerError),
     #[error("Response not ready")]
     ResponseNotReady(SelfAddressingIdentifier),
+    #[error("Message {0} waits until events of its signer are found")]
+    Deferred(SelfAddressingIdentifier, Option<String>),
+    #[error("Unknown response said: {0}")]
+    UnknownResponse(SelfAddressingIdentifier),
+    #[error("Message couldn't be processed: {0}")]
+    ResponseFailed(String),
+    #[error("Verification of message {0} expired")]
+    ResponseExpired(SelfAddressingIdentifier),
     #[error("Unparsable: {0}")]
     Unparsable(String),
     #[error(transparent)]
     SigningError(#[from] KeysError),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("Identifier {0} is not allowed to access mailbox of {1}")]
+    AccessDenied(String, String),
+    #[error("Identifier {0} is not allowed to manage devices of {1}")]
+    RegistrationDenied(String, String),
+    #[error("Identifier {0} is not allowed to send messages to {1}")]
+    SenderRejected(String, String),
+    #[error("Message of {0} bytes exceeds mailbox limit of {1} bytes")]
+    MessageTooLarge(usize, usize),
+    #[error("Too many messages for identifier {0}")]
+    RateLimited(String),
+    #[error("Notification error: {0}")]
+    Notification(String),
+    #[error("Notification rejected: {0}")]
+    NotificationRejected(String),
+    #[error("Notification target is no longer valid: {0}")]
+    InvalidNotificationTarget(String),
+    #[error("Webhook URL is not allowed: {0}")]
+    WebhookRejected(String),
+    #[error("Push endpoint is not allowed: {0}")]
+    EndpointRejected(String),
+}
+
+impl From<sled::Error> for MessageboxError {
+    fn from(err: sled::Error) -> Self {
+        MessageboxError::Storage(err.to_string())
+    }
 }
 
 pub fn register_token(id: String, token: String) -> MessageType {
-    MessageType::Exn(ExchangeArguments::SetFirebase { i: id, f: token })
+    register_device_token(id, token, DEFAULT_LABEL.to_string())
+}
+
+pub fn register_device_token(id: String, token: String, label: String) -> MessageType {
+    MessageType::Exn(ExchangeArguments::SetFirebase {
+        i: id,
+        f: token,
+        l: label,
+    })
+}
+
+pub fn register_web_push(
+    id: String,
+    subscription: WebPushSubscription,
+    label: String,
+) -> MessageType {
+    MessageType::Exn(ExchangeArguments::SetWebPush {
+        i: id,
+        s: subscription,
+        l: label,
+    })
+}
+
+pub fn set_notification_preferences(
+    id: String,
+    preferences: NotificationPreferences,
+) -> MessageType {
+    MessageType::Exn(ExchangeArguments::SetNotificationPreferences {
+        i: id,
+        n: preferences,
+    })
+}
+
+pub fn set_webhook(id: String, webhook: Option<OutgoingWebhook>) -> MessageType {
+    MessageType::Exn(ExchangeArguments::SetWebhook { i: id, w: webhook })
+}
+
+pub fn list_webhook_deliveries(id: String) -> MessageType {
+    MessageType::Exn(ExchangeArguments::ListDeliveries { i: id })
+}
+
+pub fn list_devices(id: String) -> MessageType {
+    MessageType::Exn(ExchangeArguments::ListDevices { i: id })
+}
+
+pub fn remove_device(id: String, label: String) -> MessageType {
+    MessageType::Exn(ExchangeArguments::RemoveDevice { i: id, l: label })
+}
+
+pub fn ack_by_digest(receiver: String, digests: Vec<String>) -> MessageType {
+    MessageType::Exn(ExchangeArguments::Ack {
+        i: receiver,
+        d: digests,
+        s: None,
+    })
+}
+
+pub fn ack_up_to_sn(receiver: String, sn: usize) -> MessageType {
+    MessageType::Exn(ExchangeArguments::Ack {
+        i: receiver,
+        d: vec![],
+        s: Some(sn),
+    })
+}
+
+pub fn set_readers(id: String, readers: Vec<String>) -> MessageType {
+    MessageType::Exn(ExchangeArguments::SetReaders { i: id, a: readers })
+}
+
+pub fn set_allowed_senders(id: String, senders: Option<Vec<String>>) -> MessageType {
+    MessageType::Exn(ExchangeArguments::SetAllowedSenders { i: id, a: senders })
+}
+
+pub fn set_blocked_senders(id: String, senders: Vec<String>) -> MessageType {
+    MessageType::Exn(ExchangeArguments::SetBlockedSenders { i: id, a: senders })
 }
 
 pub fn forward_message(receiver: String, data: String) -> MessageType {
@@ -76,3 +191,12 @@ pub fn send(message: &str, url: Url) -> Result<(), MessageboxError> {
         .map_err(|e| MessageboxError::Communication(e.to_string()))?;
     Ok(())
 }
+
+/// Returns random, base64url encoded value, that can't be guessed.
+pub(crate) fn random_token() -> Result<String, MessageboxError> {
+    let mut token = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| MessageboxError::VerificationFailure)?;
+    Ok(URL_SAFE_NO_PAD.encode(token))
+}
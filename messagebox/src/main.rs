@@ -1,14 +1,17 @@
 use std::{net::Ipv4Addr, path::PathBuf};
 
 use anyhow::{Context, Result};
-use clap::{arg, Parser};
+use clap::Parser;
 use figment::{
     providers::{Format, Serialized, Yaml},
     Figment,
 };
 use keri_controller::LocationScheme;
 use messagebox::{
-    messagebox::MessageBox, messagebox_listener::MessageBoxListener, MessageboxError,
+    messagebox::{MessageBox, MessageBoxOptions},
+    messagebox_listener::MessageBoxListener,
+    storage::Stores,
+    MessageboxError,
 };
 use serde::{Deserialize, Serialize};
 use url::Url;
@@ -19,6 +22,11 @@ pub struct Config {
 
     db_path: PathBuf,
 
+    /// Path to the database where forwarded messages and notification
+    /// registrations are kept. If not set, they are kept in memory and lost
+    /// on restart.
+    mailbox_db_path: Option<PathBuf>,
+
     watcher_oobi: String,
 
     /// Public URL used to advertise itself to other actors using OOBI.
@@ -30,8 +38,12 @@ pub struct Config {
     /// Witness keypair seed
     seed: Option<String>,
 
-    /// Firebase server key
+    /// Deprecated Firebase legacy server key. It's ignored, notifications
+    /// are sent using service account set in `fcm` section.
     server_key: Option<String>,
+
+    #[serde(flatten)]
+    options: MessageBoxOptions,
 }
 
 #[derive(Debug, Parser, Serialize)]
@@ -56,6 +68,7 @@ struct Args {
     #[serde(skip_serializing_if = "Option::is_none")]
     seed: Option<String>,
 
+    /// Deprecated, use `fcm.service_account` in config file instead.
     #[arg(short = 'k', long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     server_key: Option<String>,
@@ -73,16 +86,29 @@ async fn main() -> Result<()> {
         .extract::<Config>()
         .context("Failed to load config")?;
 
+    if cfg.server_key.is_some() {
+        println!(
+            "Warning: `server_key` is deprecated and ignored, since FCM legacy API is shut down. \
+            Set service account file in `fcm.service_account` config instead."
+        );
+    }
+
     let watcher_oobi: LocationScheme =
         serde_json::from_str(&cfg.watcher_oobi).map_err(|_e| MessageboxError::OobiParsingError)?;
 
+    let stores = match &cfg.mailbox_db_path {
+        Some(path) => Stores::open(path)?,
+        None => Stores::in_memory(),
+    };
+
     let data = MessageBox::setup(
         &cfg.db_path,
         &cfg.oobi_path,
         watcher_oobi,
         cfg.public_url,
         cfg.seed,
-        cfg.server_key,
+        stores,
+        cfg.options,
     )
     .await?;
     let messagebox_oobi = data.oobi();
@@ -1,4 +1,7 @@
-use std::{path::Path, sync::Arc};
+use std::{net::IpAddr, path::Path, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
 
 use keri_core::actor::prelude::SelfAddressingIdentifier;
 use keri_core::{
@@ -12,10 +15,53 @@ use keri_core::{
 };
 
 use crate::{
-    notifier::NotifyHandle, oobis::OobiHandle, responses_store::ResponsesHandle,
-    storage::StorageHandle, validate::ValidateHandle, verify::VerifyHandle, MessageboxError,
+    notifier::{NotifierConfig, NotifyHandle, NotifyOptions, WebPushClient, WebPushConfig},
+    oobis::OobiHandle,
+    ratelimit::{MessageLimiter, RateLimiter, RateLimits},
+    responses_store::{
+        ResponseStatus, ResponsesHandle, ResponsesOptions, StatusRecord, StoredResponse,
+    },
+    storage::{RetentionPolicy, StorageHandle, Stores},
+    validate::{MessageType, QueryArguments, ValidateHandle},
+    verify::VerifyHandle,
+    MessageboxError,
 };
 
+/// Tunable settings of messagebox.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MessageBoxOptions {
+    /// Service used to notify devices about new messages.
+    #[serde(default)]
+    pub notifier: NotifierConfig,
+
+    /// Web Push settings. If not set, identifiers can't be notified with
+    /// Web Push.
+    pub web_push: Option<WebPushConfig>,
+
+    /// Expiry of registrations and retries of notifications.
+    #[serde(flatten)]
+    pub notify: NotifyOptions,
+
+    /// Limits of messages kept for each identifier.
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+
+    /// Limits of messages processed for each identifier.
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+
+    /// Seconds for which long-polling request waits for new messages, 30 by
+    /// default.
+    pub long_poll_timeout: Option<u64>,
+
+    /// How long messages wait for verification and their responses are
+    /// kept.
+    #[serde(flatten)]
+    pub responses: ResponsesOptions,
+}
+
+const DEFAULT_LONG_POLL_TIMEOUT: u64 = 30;
+
 #[derive(Clone)]
 pub struct MessageBox {
     signer: Arc<Signer>,
@@ -25,6 +71,13 @@ pub struct MessageBox {
     pub verify_handle: VerifyHandle,
     pub validator_handle: ValidateHandle,
     pub response_handle: ResponsesHandle,
+    pub notify_handle: NotifyHandle,
+    pub(crate) storage_handle: StorageHandle,
+    /// VAPID public key, that user agents need to subscribe to Web Push.
+    pub vapid_public_key: Option<String>,
+    limiter: MessageLimiter,
+    deferred_limiter: Option<RateLimiter>,
+    long_poll_timeout: Duration,
 }
 
 impl MessageBox {
@@ -34,7 +87,32 @@ impl MessageBox {
         watcher_oobi: LocationScheme,
         address: url::Url,
         seed: Option<String>,
-        server_key: Option<String>,
+        stores: Stores,
+        options: MessageBoxOptions,
+    ) -> Result<Self, MessageboxError> {
+        Self::start(
+            kel_path,
+            oobi_path,
+            Some(watcher_oobi),
+            address,
+            seed,
+            stores,
+            options,
+        )
+        .await
+    }
+
+    /// Sets up messagebox, that asks `watcher_oobi` for events of unknown
+    /// signers. If watcher isn't set, only messages of already known
+    /// signers are accepted.
+    pub(crate) async fn start(
+        kel_path: &Path,
+        oobi_path: &Path,
+        watcher_oobi: Option<LocationScheme>,
+        address: url::Url,
+        seed: Option<String>,
+        stores: Stores,
+        options: MessageBoxOptions,
     ) -> Result<Self, MessageboxError> {
         let signer = Arc::new(
             seed.map(|key| Signer::new_with_seed(&key.parse()?))
@@ -59,23 +137,38 @@ impl MessageBox {
             id.clone(),
             SelfSigningPrefix::Ed25519Sha512(signer.sign(reply.encode()?)?),
         );
-        let notify_handle = if let Some(key) = server_key {
-            println!("Firebase server key set: {}", &key);
-            NotifyHandle::new(key)
-        } else {
-            todo!("Firebase server_key is mandatory for now")
-        };
-        let storage_handle = StorageHandle::new(notify_handle.clone());
+        let web_push = options
+            .web_push
+            .as_ref()
+            .map(WebPushClient::load)
+            .transpose()?;
+        let vapid_public_key = web_push.as_ref().map(WebPushClient::public_key);
+        let notify_handle = NotifyHandle::new(
+            options.notifier.backend()?,
+            web_push,
+            stores.registrations,
+            options.notify,
+        );
+        let storage_handle =
+            StorageHandle::new(stores.mailbox, options.retention, notify_handle.clone());
         let oobi_handle = OobiHandle::new(oobi_path);
         oobi_handle.register(vec![signed_reply]).await;
-        let response_handle = ResponsesHandle::new();
+        let response_handle = ResponsesHandle::new(stores.responses, options.responses);
+        let limiter = MessageLimiter::new(&options.rate_limits, storage_handle.clone());
         let validator_handle = ValidateHandle::new(
             storage_handle.clone(),
-            notify_handle,
+            notify_handle.clone(),
             response_handle.clone(),
+            limiter.clone(),
         );
-        let verify_handle =
-            VerifyHandle::new(kel_path, watcher_oobi, validator_handle.clone()).await?;
+        let verify_handle = VerifyHandle::start(
+            kel_path,
+            watcher_oobi,
+            validator_handle.clone(),
+            response_handle.clone(),
+            stores.reverify,
+        )
+        .await?;
         Ok(Self {
             public_address: address,
             signer,
@@ -84,25 +177,118 @@ impl MessageBox {
             validator_handle,
             verify_handle,
             response_handle,
+            notify_handle,
+            storage_handle,
+            vapid_public_key,
+            limiter,
+            deferred_limiter: options.rate_limits.deferred.map(RateLimiter::new),
+            long_poll_timeout: Duration::from_secs(
+                options
+                    .long_poll_timeout
+                    .unwrap_or(DEFAULT_LONG_POLL_TIMEOUT),
+            ),
         })
     }
 
-    pub async fn process_message(&self, body: String) -> Result<Option<String>, MessageboxError> {
-        let (data, signatures) = Self::split_cesr_stream(body.as_bytes())?;
-        let payload_str =
-            String::from_utf8(data).map_err(|e| MessageboxError::Unparsable(e.to_string()))?;
+    /// Processes signed message sent from `peer` address.
+    pub async fn process_message(
+        &self,
+        body: String,
+        peer: Option<IpAddr>,
+    ) -> Result<Option<String>, MessageboxError> {
+        let (signer, payload_str) = self.verify_message(&body, peer).await?;
+        self.limiter.check(&signer, &payload_str).await?;
+        self.validator_handle
+            .validate(signer, payload_str, body)
+            .await
+    }
+
+    /// Verifies signatures of CESR `body`. Returns identifier that signed
+    /// it and its payload. If events of signer need to be found first,
+    /// message is queued and `Deferred` with its digest and token is
+    /// returned. Signer isn't verified yet then, so number of queued
+    /// messages is limited for `peer` address that sent them.
+    pub async fn verify_message(
+        &self,
+        body: &str,
+        peer: Option<IpAddr>,
+    ) -> Result<(String, String), MessageboxError> {
+        let (payload_str, signatures) = Self::split_signed(body)?;
         match self
             .verify_handle
-            .verify(&payload_str, signatures.collect())
+            .verify(&payload_str, signatures.clone())
             .await
         {
-            Ok(_) => self.validator_handle.validate(payload_str).await,
-            // Err(MessageboxError::MissingEvent(id, dig )) => {
-            // },
+            Ok(signer) => Ok((signer.to_string(), payload_str)),
+            Err(MessageboxError::MissingEvent(signer, _)) => {
+                // Limits of signer are checked after its events are found.
+                if let (Some(limiter), Some(peer)) = (&self.deferred_limiter, peer) {
+                    limiter.check(&peer.to_string())?;
+                };
+                let (said, token) = self
+                    .verify_handle
+                    .defer(signer, payload_str, signatures, body.to_string())
+                    .await?;
+                Err(MessageboxError::Deferred(said, token))
+            }
             Err(e) => Err(e),
         }
     }
 
+    /// Verifies signatures of CESR `body` like `verify_message`, but
+    /// returns error instead of queuing it, if events of signer are
+    /// missing.
+    pub async fn verify_now(&self, body: &str) -> Result<(String, String), MessageboxError> {
+        let (payload_str, signatures) = Self::split_signed(body)?;
+        let signer = self.verify_handle.verify(&payload_str, signatures).await?;
+        Ok((signer.to_string(), payload_str))
+    }
+
+    /// Processes signed query of messages by index (`BySn`). If there are
+    /// no such messages, waits until message for queried identifier is
+    /// saved, or long-polling timeout passes, and queries again.
+    pub async fn wait_for_messages(
+        &self,
+        body: String,
+        peer: Option<IpAddr>,
+    ) -> Result<Option<String>, MessageboxError> {
+        let (signer, payload_str) = self.verify_message(&body, peer).await?;
+        self.limiter.check(&signer, &payload_str).await?;
+        let identifier = match serde_json::from_str(&payload_str) {
+            Ok(MessageType::Qry(QueryArguments::BySn { i, .. })) => i,
+            _ => return Err(MessageboxError::UnknownMessage(payload_str)),
+        };
+
+        // Subscribe before querying, not to miss message saved in between.
+        let mut saved = self.storage_handle.subscribe();
+        let response = self
+            .validator_handle
+            .validate(signer.clone(), payload_str.clone(), body.clone())
+            .await?;
+        if has_messages(&response) {
+            return Ok(response);
+        };
+        let new_message = async {
+            loop {
+                match saved.recv().await {
+                    Ok(key) if key == identifier => break,
+                    Ok(_) => continue,
+                    // Some identifiers were skipped, so query to be sure.
+                    Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => std::future::pending().await,
+                }
+            }
+        };
+        match tokio::time::timeout(self.long_poll_timeout, new_message).await {
+            Ok(()) => {
+                self.validator_handle
+                    .validate(signer, payload_str, body)
+                    .await
+            }
+            Err(_) => Ok(response),
+        }
+    }
+
     pub async fn resolve_oobi(&self, oobi: String) -> Result<(), MessageboxError> {
         self.verify_handle.resolve_oobi(oobi).await
     }
@@ -138,8 +324,101 @@ impl MessageBox {
             .transpose()
     }
 
-    pub async fn get_responses(&self, sai: SelfAddressingIdentifier) -> Option<String> {
-        self.response_handle.get_by_digest(sai).await
+    /// Returns deferred response to message with digest `said`. Response is
+    /// released only if `body` is `{"d": <said>}`, signed by identifier that
+    /// sent the message. Until events of that identifier are found, `token`
+    /// returned when message was deferred is required instead.
+    pub async fn get_response(
+        &self,
+        said: SelfAddressingIdentifier,
+        body: &str,
+        token: Option<&str>,
+    ) -> Result<Option<String>, MessageboxError> {
+        let response = self.requested_response(said.clone(), body, token).await?;
+        match response.status {
+            ResponseStatus::Reverified(result) => {
+                self.response_handle.fetched(response.requester, said).await;
+                Ok(result)
+            }
+            ResponseStatus::Failed(e) => {
+                self.response_handle.fetched(response.requester, said).await;
+                Err(MessageboxError::ResponseFailed(e))
+            }
+            ResponseStatus::Queued | ResponseStatus::AskingWatcher => {
+                Err(MessageboxError::ResponseNotReady(said))
+            }
+            ResponseStatus::Expired => Err(MessageboxError::ResponseExpired(said)),
+        }
+    }
+
+    /// Returns progress of verification of message with digest `said`. It's
+    /// returned to the same requester as response.
+    pub async fn get_response_status(
+        &self,
+        said: SelfAddressingIdentifier,
+        body: &str,
+        token: Option<&str>,
+    ) -> Result<StatusRecord, MessageboxError> {
+        Ok(self.requested_response(said, body, token).await?.record())
+    }
+
+    /// Checks if `body` is `{"d": <said>}` and returns deferred response to
+    /// message with digest `said`, sent by identifier that signed `body`.
+    /// If events of signer aren't known, signature can't be verified, so
+    /// `token` of response is checked instead.
+    async fn requested_response(
+        &self,
+        said: SelfAddressingIdentifier,
+        body: &str,
+        token: Option<&str>,
+    ) -> Result<StoredResponse, MessageboxError> {
+        if body.is_empty() {
+            return Err(MessageboxError::VerificationFailure);
+        };
+        let (payload, signatures) = Self::split_signed(body)?;
+        let requested: serde_json::Value = serde_json::from_str(&payload)
+            .map_err(|_| MessageboxError::UnknownMessage(payload.clone()))?;
+        if requested["d"].as_str() != Some(&said.to_string()) {
+            return Err(MessageboxError::VerificationFailure);
+        };
+        match self
+            .verify_handle
+            .verify(&payload, signatures.clone())
+            .await
+        {
+            Ok(signer) => self
+                .response_handle
+                .get(signer.to_string(), said.clone())
+                .await?
+                .ok_or(MessageboxError::UnknownResponse(said)),
+            Err(MessageboxError::MissingEvent(_, _) | MessageboxError::MissingOobi) => {
+                let requester = signatures
+                    .iter()
+                    .find_map(|signature| match signature {
+                        Signature::Transferable(signer_data, _) => signer_data.get_signer(),
+                        _ => None,
+                    })
+                    .ok_or(MessageboxError::VerificationFailure)?;
+                match (
+                    self.response_handle
+                        .get(requester.to_string(), said)
+                        .await?,
+                    token,
+                ) {
+                    (Some(response), Some(token)) if response.has_token(token) => Ok(response),
+                    _ => Err(MessageboxError::VerificationFailure),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Splits CESR `body` into payload and its signatures.
+    fn split_signed(body: &str) -> Result<(String, Vec<Signature>), MessageboxError> {
+        let (data, signatures) = Self::split_cesr_stream(body.as_bytes())?;
+        let payload_str =
+            String::from_utf8(data).map_err(|e| MessageboxError::Unparsable(e.to_string()))?;
+        Ok((payload_str, signatures.collect()))
     }
 
     fn split_cesr_stream(
@@ -155,16 +434,296 @@ impl MessageBox {
         let signatures = parsed_data
             .attachments
             .into_iter()
-            .map(|g| get_signatures(g))
+            .map(get_signatures)
             // This ignore errors while getting signatures
-            .filter_map(|sig| {
-                if let Ok(signature) = sig {
-                    Some(signature)
-                } else {
-                    None
-                }
-            })
+            .filter_map(|sig| sig.ok())
             .flatten();
         Ok((data, signatures))
     }
 }
+
+/// Checks if response to query of messages contains any message.
+pub(crate) fn has_messages(response: &Option<String>) -> bool {
+    response
+        .as_ref()
+        .and_then(|response| serde_json::from_str::<serde_json::Value>(response).ok())
+        .and_then(|response| {
+            response["messages"]
+                .as_array()
+                .map(|messages| !messages.is_empty())
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use cesrox::group::Group;
+    use keri_core::{
+        event::sections::seal::EventSeal,
+        event_message::signature::{Nontransferable, Signature, SignerData},
+        prefix::{BasicPrefix, IdentifierPrefix, IndexedSignature, SelfSigningPrefix},
+        signer::Signer,
+    };
+    use serde_json::json;
+    use tempfile::{Builder, TempDir};
+    use tokio::time::{sleep, Duration};
+
+    use crate::{
+        forward_message,
+        ratelimit::{RateLimit, RateLimits},
+        responses_store::{digest, ResponseStatus},
+        storage::Stores,
+        MessageboxError,
+    };
+
+    use super::{MessageBox, MessageBoxOptions};
+
+    /// Sets up messagebox without watcher, that accepts messages with
+    /// nontransferable signatures. Returned directories need to be kept
+    /// while it's used.
+    pub(crate) async fn setup() -> (MessageBox, TempDir, TempDir) {
+        setup_with(MessageBoxOptions::default()).await
+    }
+
+    /// Sets up messagebox like `setup`, with given `options`.
+    pub(crate) async fn setup_with(options: MessageBoxOptions) -> (MessageBox, TempDir, TempDir) {
+        let kel_root = Builder::new().prefix("test-db").tempdir().unwrap();
+        let oobi_root = Builder::new().prefix("test-db").tempdir().unwrap();
+        let messagebox = MessageBox::start(
+            kel_root.path(),
+            oobi_root.path(),
+            None,
+            url::Url::parse("http://localhost").unwrap(),
+            None,
+            Stores::in_memory(),
+            options,
+        )
+        .await
+        .unwrap();
+        (messagebox, kel_root, oobi_root)
+    }
+
+    /// Returns `payload` with nontransferable signature of `signer`
+    /// attached.
+    pub(crate) fn sign(signer: &Signer, payload: &str) -> String {
+        let signature = Signature::NonTransferable(Nontransferable::Couplet(vec![(
+            BasicPrefix::Ed25519NT(signer.public_key()),
+            SelfSigningPrefix::Ed25519Sha512(signer.sign(payload).unwrap()),
+        )]));
+        let attachment: Group = signature.into();
+        format!("{}{}", payload, attachment.to_cesr_str())
+    }
+
+    /// Returns `payload` signed by `signer` as transferable identifier,
+    /// whose events aren't known, and that identifier.
+    pub(crate) fn sign_unknown(signer: &Signer, payload: &str) -> (String, String) {
+        let identifier = IdentifierPrefix::SelfAddressing(digest("unknown"));
+        let seal = EventSeal {
+            prefix: identifier.clone(),
+            sn: 0,
+            event_digest: digest("icp"),
+        };
+        let signature = Signature::Transferable(
+            SignerData::EventSeal(seal),
+            vec![IndexedSignature::new_both_same(
+                SelfSigningPrefix::Ed25519Sha512(signer.sign(payload).unwrap()),
+                0,
+            )],
+        );
+        let attachment: Group = signature.into();
+        (
+            format!("{}{}", payload, attachment.to_cesr_str()),
+            identifier.to_string(),
+        )
+    }
+
+    pub(crate) fn identifier(signer: &Signer) -> String {
+        IdentifierPrefix::Basic(BasicPrefix::Ed25519NT(signer.public_key())).to_string()
+    }
+
+    #[actix_web::test]
+    async fn test_response_status() -> Result<(), MessageboxError> {
+        let (messagebox, _kel_root, _oobi_root) = setup().await;
+        let (alice, bob) = (Signer::new(), Signer::new());
+        let said = digest(r#"{"m":"hi there"}"#);
+        messagebox
+            .response_handle
+            .save(
+                said.clone(),
+                identifier(&alice),
+                ResponseStatus::Failed("Verification failed".into()),
+            )
+            .await?;
+        let request = json!({ "d": said.to_string() }).to_string();
+
+        // Status is shown only to identifier that sent the message.
+        assert!(matches!(
+            messagebox.get_response_status(said.clone(), "", None).await,
+            Err(MessageboxError::VerificationFailure)
+        ));
+        assert!(matches!(
+            messagebox
+                .get_response_status(said.clone(), &sign(&bob, &request), None)
+                .await,
+            Err(MessageboxError::UnknownResponse(_))
+        ));
+        let other = json!({"d": digest("other").to_string()}).to_string();
+        assert!(matches!(
+            messagebox
+                .get_response_status(said.clone(), &sign(&alice, &other), None)
+                .await,
+            Err(MessageboxError::VerificationFailure)
+        ));
+
+        let record = messagebox
+            .get_response_status(said.clone(), &sign(&alice, &request), None)
+            .await?;
+        assert_eq!(record.status, "failed");
+        assert_eq!(record.reason.as_deref(), Some("Verification failed"));
+        assert!(matches!(
+            messagebox
+                .get_response(said, &sign(&alice, &request), None)
+                .await,
+            Err(MessageboxError::ResponseFailed(_))
+        ));
+        Ok(())
+    }
+    #[actix_web::test]
+    async fn test_deferred_status() -> Result<(), MessageboxError> {
+        let (messagebox, _kel_root, _oobi_root) = setup().await;
+        let said = digest(r#"{"m":"hi there"}"#);
+        let request = json!({ "d": said.to_string() }).to_string();
+        let (signed_request, requester) = sign_unknown(&Signer::new(), &request);
+        let token = messagebox
+            .response_handle
+            .defer(said.clone(), requester.clone())
+            .await?
+            .unwrap();
+
+        // Signature of requester can't be verified yet, so token is needed.
+        let record = messagebox
+            .get_response_status(said.clone(), &signed_request, Some(&token))
+            .await?;
+        assert_eq!(record.status, "queued");
+        messagebox
+            .response_handle
+            .update_waiting(requester, ResponseStatus::AskingWatcher)
+            .await;
+        let record = messagebox
+            .get_response_status(said.clone(), &signed_request, Some(&token))
+            .await?;
+        assert_eq!(record.status, "asking_watcher");
+
+        for token in [None, Some("wrong")] {
+            assert!(matches!(
+                messagebox
+                    .get_response_status(said.clone(), &signed_request, token)
+                    .await,
+                Err(MessageboxError::VerificationFailure)
+            ));
+        }
+        // Token of message doesn't give access to other messages.
+        assert!(matches!(
+            messagebox
+                .get_response_status(digest("other"), &signed_request, Some(&token))
+                .await,
+            Err(MessageboxError::VerificationFailure)
+        ));
+        Ok(())
+    }
+    #[actix_web::test]
+    async fn test_deferred_response() -> Result<(), MessageboxError> {
+        let (messagebox, _kel_root, _oobi_root) = setup().await;
+        let said = digest(r#"{"m":"hi there"}"#);
+        let request = json!({ "d": said.to_string() }).to_string();
+        let (signed_request, requester) = sign_unknown(&Signer::new(), &request);
+        let token = messagebox
+            .response_handle
+            .defer(said.clone(), requester.clone())
+            .await?
+            .unwrap();
+        assert!(matches!(
+            messagebox
+                .get_response(said.clone(), &signed_request, Some(&token))
+                .await,
+            Err(MessageboxError::ResponseNotReady(_))
+        ));
+
+        messagebox
+            .response_handle
+            .save(
+                said.clone(),
+                requester,
+                ResponseStatus::Reverified(Some("response".into())),
+            )
+            .await?;
+        assert!(matches!(
+            messagebox
+                .get_response(said.clone(), &signed_request, Some("wrong"))
+                .await,
+            Err(MessageboxError::VerificationFailure)
+        ));
+        // Identifier that didn't send the message can't use its token.
+        assert!(matches!(
+            messagebox
+                .get_response(said.clone(), &sign(&Signer::new(), &request), Some(&token))
+                .await,
+            Err(MessageboxError::UnknownResponse(_))
+        ));
+        assert_eq!(
+            messagebox
+                .get_response(said, &signed_request, Some(&token))
+                .await?,
+            Some("response".to_string())
+        );
+        Ok(())
+    }
+    #[actix_web::test]
+    async fn test_reverified_limits() -> Result<(), MessageboxError> {
+        let options = MessageBoxOptions {
+            rate_limits: RateLimits {
+                sender: Some(RateLimit {
+                    burst: 1,
+                    per_second: 0.001,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (messagebox, _kel_root, _oobi_root) = setup_with(options).await;
+        let (alice, bob) = (Signer::new(), Signer::new());
+
+        // Messages that waited for events of signer are counted against its
+        // limit once they are verified.
+        let mut saids = vec![];
+        for text in ["hi", "hi again"] {
+            let fwd = forward_message(identifier(&bob), text.to_string()).to_string();
+            messagebox
+                .validator_handle
+                .process_and_save(identifier(&alice), fwd.clone(), sign(&alice, &fwd))
+                .await;
+            saids.push(digest(&fwd));
+        }
+        sleep(Duration::from_millis(100)).await;
+        let first = messagebox
+            .response_handle
+            .get(identifier(&alice), saids[0].clone())
+            .await?
+            .unwrap();
+        assert!(matches!(first.status, ResponseStatus::Reverified(_)));
+        let second = messagebox
+            .response_handle
+            .get(identifier(&alice), saids[1].clone())
+            .await?
+            .unwrap();
+        assert!(matches!(second.status, ResponseStatus::Failed(_)));
+
+        // The same limit applies to messages verified at once.
+        let fwd = forward_message(identifier(&bob), "hi bob".to_string()).to_string();
+        assert!(matches!(
+            messagebox.process_message(sign(&alice, &fwd), None).await,
+            Err(MessageboxError::RateLimited(_))
+        ));
+        Ok(())
+    }
+}
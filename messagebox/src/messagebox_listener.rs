@@ -4,10 +4,14 @@ use actix_web::{
 };
 use anyhow::Result;
 use keri_controller::IdentifierPrefix;
-use keri_core::actor::prelude::SelfAddressingIdentifier;
 use keri_core::{database::DbError, event_message::cesr_adapter::ParseError, oobi::Role};
 use std::{net::ToSocketAddrs, sync::Arc};
 
+/// Header with token of deferred message. It's returned with `202 Accepted`
+/// and needs to be sent back when asking for response or its status, until
+/// events of signer are found.
+pub const TOKEN_HEADER: &str = "X-Messagebox-Token";
+
 pub struct MessageBoxListener {
     pub messagebox: MessageBox,
 }
@@ -38,14 +42,43 @@ impl MessageBoxListener {
                     "/",
                     actix_web::web::post().to(http_handlers::process_message),
                 )
+                .route(
+                    "/poll",
+                    actix_web::web::post().to(http_handlers::poll_messages),
+                )
+                .route("/ws", actix_web::web::get().to(crate::websocket::connect))
                 .route(
                     "/resolve",
                     actix_web::web::post().to(http_handlers::resolve_oobi),
                 )
+                .route(
+                    "/webpush/key",
+                    actix_web::web::get().to(http_handlers::get_vapid_key),
+                )
+                .route(
+                    "/notifications/stats",
+                    actix_web::web::get().to(http_handlers::get_notification_stats),
+                )
+                .route(
+                    "/webhooks/stats",
+                    actix_web::web::get().to(http_handlers::get_webhook_stats),
+                )
                 .route(
                     "/messages/{said}",
                     actix_web::web::get().to(http_handlers::get_response),
                 )
+                .route(
+                    "/messages/{said}",
+                    actix_web::web::post().to(http_handlers::get_response),
+                )
+                .route(
+                    "/messages/{said}/status",
+                    actix_web::web::get().to(http_handlers::get_response_status),
+                )
+                .route(
+                    "/messages/{said}/status",
+                    actix_web::web::post().to(http_handlers::get_response_status),
+                )
         })
         .bind(addr)?
         .run())
@@ -56,7 +89,7 @@ mod http_handlers {
     use std::sync::Arc;
 
     use crate::{messagebox::MessageBox, MessageboxError};
-    use actix_web::{http::header::ContentType, web, HttpResponse};
+    use actix_web::{http::header::ContentType, web, HttpRequest, HttpResponse};
     use keri_core::actor::prelude::SelfAddressingIdentifier;
     use keri_core::{
         actor::parse_reply_stream,
@@ -66,7 +99,7 @@ mod http_handlers {
         query::reply_event::SignedReply,
     };
 
-    use super::ApiError;
+    use super::{ApiError, TOKEN_HEADER};
 
     fn oobis_to_cesr_stream(
         oobis: &mut impl Iterator<Item = SignedReply>,
@@ -83,6 +116,25 @@ mod http_handlers {
         Ok(HttpResponse::Ok().json(data.oobi()))
     }
 
+    /// Returns VAPID public key needed to subscribe to Web Push.
+    pub async fn get_vapid_key(data: web::Data<Arc<MessageBox>>) -> HttpResponse {
+        match &data.vapid_public_key {
+            Some(key) => HttpResponse::Ok().body(key.clone()),
+            None => HttpResponse::NotFound().body("Web Push is not configured"),
+        }
+    }
+
+    /// Returns number of notifications by delivery outcome.
+    pub async fn get_notification_stats(data: web::Data<Arc<MessageBox>>) -> HttpResponse {
+        HttpResponse::Ok().json(data.notify_handle.stats())
+    }
+
+    /// Returns number of events POSTed to outgoing webhooks by delivery
+    /// outcome.
+    pub async fn get_webhook_stats(data: web::Data<Arc<MessageBox>>) -> HttpResponse {
+        HttpResponse::Ok().json(data.notify_handle.webhook_stats())
+    }
+
     /// Returns stream of signed reply messages that has endpoint identifier
     /// location schemas inside.
     pub async fn get_eid_oobi(
@@ -113,7 +165,7 @@ mod http_handlers {
             &mut end_role
                 .ok_or(ApiError::MissingEndRoleOobi(cid, role))?
                 .into_iter()
-                .chain(loc_scheme?.unwrap_or_default().into_iter()),
+                .chain(loc_scheme?.unwrap_or_default()),
         )?;
 
         Ok(HttpResponse::Ok()
@@ -122,13 +174,50 @@ mod http_handlers {
     }
 
     pub async fn process_message(
+        req: HttpRequest,
+        body: String,
+        data: web::Data<Arc<MessageBox>>,
+    ) -> Result<HttpResponse, ApiError> {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        Ok(to_response(data.process_message(body, peer).await))
+    }
+
+    /// Long-polling query of new messages. Responds when messages are
+    /// available or timeout passes.
+    pub async fn poll_messages(
+        req: HttpRequest,
         body: String,
         data: web::Data<Arc<MessageBox>>,
     ) -> Result<HttpResponse, ApiError> {
-        Ok(match data.process_message(body).await {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        Ok(to_response(data.wait_for_messages(body, peer).await))
+    }
+
+    fn to_response(result: Result<Option<String>, MessageboxError>) -> HttpResponse {
+        match result {
             Ok(Some(response)) => HttpResponse::Ok().body(response),
             Ok(None) => HttpResponse::Ok().finish(),
             Err(MessageboxError::VerificationFailure) => HttpResponse::Unauthorized().finish(),
+            Err(
+                err @ (MessageboxError::AccessDenied(_, _)
+                | MessageboxError::RegistrationDenied(_, _)
+                | MessageboxError::SenderRejected(_, _)),
+            ) => HttpResponse::Forbidden().body(err.to_string()),
+            Err(err @ MessageboxError::UnknownResponse(_)) => {
+                HttpResponse::NotFound().body(err.to_string())
+            }
+            Err(err @ MessageboxError::ResponseFailed(_)) => {
+                HttpResponse::UnprocessableEntity().body(err.to_string())
+            }
+            Err(err @ MessageboxError::ResponseExpired(_)) => {
+                HttpResponse::Gone().body(err.to_string())
+            }
+            Err(err @ MessageboxError::MessageTooLarge(_, _)) => {
+                HttpResponse::PayloadTooLarge().body(err.to_string())
+            }
+            Err(err @ MessageboxError::RateLimited(_)) => {
+                HttpResponse::TooManyRequests().body(err.to_string())
+            }
             Err(MessageboxError::ResponseNotReady(said)) => {
                 let message = format!(
                     "Missing event, need to ask later on `/messages/{}` endpoint.",
@@ -136,6 +225,17 @@ mod http_handlers {
                 );
                 HttpResponse::Accepted().body(message)
             }
+            Err(MessageboxError::Deferred(said, token)) => {
+                let message = format!(
+                    "Missing event, need to ask later on `/messages/{}` endpoint.",
+                    said
+                );
+                let mut response = HttpResponse::Accepted();
+                if let Some(token) = token {
+                    response.insert_header((TOKEN_HEADER, token));
+                };
+                response.body(message)
+            }
             Err(MessageboxError::MissingOobi) => {
                 let message =
                     "Missing oobi, need to be provided to `/resolve` endpoint.".to_string();
@@ -145,7 +245,7 @@ mod http_handlers {
                 let message = format!("Message ignored due to error: {}", &err);
                 HttpResponse::BadRequest().body(message)
             }
-        })
+        }
     }
 
     pub async fn register(
@@ -176,18 +276,47 @@ mod http_handlers {
         Ok(HttpResponse::Ok().finish())
     }
 
-    pub async fn get_response(
+    /// Token of deferred message, if request has one.
+    fn token(req: &HttpRequest) -> Option<&str> {
+        req.headers()
+            .get(TOKEN_HEADER)
+            .and_then(|token| token.to_str().ok())
+    }
+
+    /// Returns progress of verification of message, that couldn't be
+    /// verified at once. Request body needs to be signed by identifier that
+    /// sent the message, and until its events are found, token of message
+    /// needs to be in `X-Messagebox-Token` header.
+    pub async fn get_response_status(
+        req: HttpRequest,
         said: web::Path<SelfAddressingIdentifier>,
+        body: String,
         data: web::Data<Arc<MessageBox>>,
     ) -> Result<HttpResponse, ApiError> {
-        println!("\nRequest responses for: \n{}", &said.to_string());
-        let sai = said.into_inner();
-        data.response_handle
-            .get_by_digest(sai.clone())
+        match data
+            .get_response_status(said.into_inner(), &body, token(&req))
             .await
-            .ok_or(ApiError::UnknownResponse(sai))?;
+        {
+            Ok(record) => Ok(HttpResponse::Ok().json(record)),
+            Err(err) => Ok(to_response(Err(err))),
+        }
+    }
 
-        Ok(HttpResponse::Ok().finish())
+    /// Returns response to message, that couldn't be verified at once.
+    /// Request body needs to be signed by identifier that sent the message,
+    /// and until its events are found, token of message needs to be in
+    /// `X-Messagebox-Token` header.
+    pub async fn get_response(
+        req: HttpRequest,
+        said: web::Path<SelfAddressingIdentifier>,
+        body: String,
+        data: web::Data<Arc<MessageBox>>,
+    ) -> Result<HttpResponse, ApiError> {
+        println!("\nRequest responses for: \n{}", &said.to_string());
+        Ok(to_response(
+            data.get_response(said.into_inner(), &body, token(&req))
+                .await,
+        ))
     }
 }
 
@@ -205,8 +334,6 @@ pub enum ApiError {
     Unparsable,
     #[error("No end role oobi of identifier: {0}, {1:?}")]
     MissingEndRoleOobi(IdentifierPrefix, Role),
-    #[error("Unknown response said: {0}")]
-    UnknownResponse(SelfAddressingIdentifier),
 }
 
 impl ResponseError for ApiError {
@@ -0,0 +1,142 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::MessageboxError;
+
+/// How failed notifications are retried. Delay between attempts starts with
+/// `initial_backoff` and doubles after every attempt, up to `max_backoff`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts to deliver notification.
+    pub max_attempts: u32,
+    /// Delay before the first retry, in milliseconds.
+    pub initial_backoff: u64,
+    /// Maximum delay between attempts, in milliseconds.
+    pub max_backoff: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: 500,
+            max_backoff: 60_000,
+        }
+    }
+}
+
+/// Number of notifications and webhook events by delivery outcome.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DeliveryStats {
+    /// Notifications delivered to push service.
+    pub delivered: u64,
+    /// Attempts that failed and were retried.
+    pub retried: u64,
+    /// Notifications that couldn't be delivered.
+    pub failed: u64,
+    /// Registrations removed because push service reported them invalid.
+    pub removed: u64,
+}
+
+#[derive(Default)]
+pub(super) struct DeliveryCounters {
+    delivered: AtomicU64,
+    retried: AtomicU64,
+    failed: AtomicU64,
+    removed: AtomicU64,
+}
+
+/// Counters of notifications and of outgoing webhook events, kept
+/// separately.
+#[derive(Default)]
+pub(super) struct NotifierCounters {
+    pub(super) notifications: DeliveryCounters,
+    pub(super) webhooks: DeliveryCounters,
+}
+
+impl DeliveryCounters {
+    pub(super) fn stats(&self) -> DeliveryStats {
+        DeliveryStats {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            removed: self.removed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(super) fn removed(&self) {
+        self.removed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Tries to deliver notification with blocking `send` function, until it
+/// succeeds, fails with error that can't be fixed by retrying or runs out of
+/// attempts.
+pub(super) async fn deliver<F>(
+    send: F,
+    policy: &RetryPolicy,
+    counters: &DeliveryCounters,
+) -> Result<(), MessageboxError>
+where
+    F: Fn() -> Result<(), MessageboxError> + Send + Sync + 'static,
+{
+    let send = Arc::new(send);
+    let mut backoff = Duration::from_millis(policy.initial_backoff);
+    let mut attempt = 1;
+    loop {
+        let task = send.clone();
+        let result = tokio::task::spawn_blocking(move || task())
+            .await
+            .unwrap_or_else(|e| Err(MessageboxError::Notification(e.to_string())));
+        match result {
+            Ok(()) => {
+                counters.delivered.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            Err(MessageboxError::Notification(e)) if attempt < policy.max_attempts => {
+                println!(
+                    "Notification attempt {} failed: {}, retrying in {:?}",
+                    attempt, e, backoff
+                );
+                counters.retried.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_millis(policy.max_backoff));
+                attempt += 1;
+            }
+            Err(e @ MessageboxError::InvalidNotificationTarget(_)) => return Err(e),
+            Err(e) => {
+                counters.failed.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Converts error of request to push service. Errors that may disappear
+/// after retry are returned as `Notification`, and those caused by invalid
+/// target, recognized by `is_invalid`, as `InvalidNotificationTarget`.
+pub(super) fn classify(
+    err: ureq::Error,
+    is_invalid: impl Fn(u16, &str) -> bool,
+) -> MessageboxError {
+    match err {
+        ureq::Error::Status(code, response) => {
+            let body = response.into_string().unwrap_or_default();
+            if is_invalid(code, &body) {
+                MessageboxError::InvalidNotificationTarget(format!("{}: {}", code, body))
+            } else if code == 429 || code >= 500 {
+                MessageboxError::Notification(format!("{}: {}", code, body))
+            } else {
+                MessageboxError::NotificationRejected(format!("{}: {}", code, body))
+            }
+        }
+        ureq::Error::Transport(transport) => MessageboxError::Notification(transport.to_string()),
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use ring::{
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use url::Url;
+
+use crate::MessageboxError;
+
+use super::{decode_pem, delivery::classify, Notification, NotificationBackend};
+
+const SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+/// How long minted access token is valid, in seconds.
+const TOKEN_LIFETIME: i64 = 3600;
+/// Access token is renewed when it expires sooner than that.
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+/// Text shown under the title of notification. Message details are passed
+/// only in `data`, so they don't show up on lock screen.
+const BODY: &str = "Open the app to read it.";
+
+/// Firebase Cloud Messaging settings.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FcmConfig {
+    /// Path to service account JSON file downloaded from Firebase console.
+    pub service_account: PathBuf,
+    /// Base URL of FCM HTTP v1 API.
+    #[serde(default = "FcmConfig::default_base_url")]
+    pub base_url: Url,
+}
+
+impl FcmConfig {
+    /// Config using service account from file `service_account` and
+    /// default FCM API URL.
+    pub fn new(service_account: PathBuf) -> Self {
+        Self {
+            service_account,
+            base_url: Self::default_base_url(),
+        }
+    }
+
+    fn default_base_url() -> Url {
+        "https://fcm.googleapis.com/".parse().unwrap()
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    private_key: String,
+    client_email: String,
+    token_uri: Url,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct AccessToken {
+    token: String,
+    expires: Instant,
+}
+
+/// Client of FCM HTTP v1 API. Authenticates with OAuth2 access tokens
+/// minted from service account key and caches them until they expire.
+pub struct FcmClient {
+    account: ServiceAccount,
+    key_pair: RsaKeyPair,
+    send_url: Url,
+    access_token: Mutex<Option<AccessToken>>,
+}
+
+impl FcmClient {
+    pub fn load(config: &FcmConfig) -> Result<Self, MessageboxError> {
+        let file = std::fs::read(&config.service_account).map_err(|e| {
+            MessageboxError::Notification(format!(
+                "can't read service account file {:?}: {}",
+                config.service_account, e
+            ))
+        })?;
+        let account: ServiceAccount = serde_json::from_slice(&file)
+            .map_err(|e| MessageboxError::Notification(e.to_string()))?;
+        let key_pair = RsaKeyPair::from_pkcs8(&decode_pem(&account.private_key)?)
+            .map_err(|e| MessageboxError::Notification(e.to_string()))?;
+        let send_url = config
+            .base_url
+            .join(&format!("v1/projects/{}/messages:send", account.project_id))
+            .map_err(|e| MessageboxError::Notification(e.to_string()))?;
+        Ok(Self {
+            account,
+            key_pair,
+            send_url,
+            access_token: Mutex::new(None),
+        })
+    }
+
+    /// Returns cached access token or mints new one, if it is about to
+    /// expire.
+    fn access_token(&self) -> Result<String, MessageboxError> {
+        let mut cached = self.access_token.lock().unwrap();
+        match cached.as_ref() {
+            Some(access_token) if access_token.expires > Instant::now() + TOKEN_MARGIN => {
+                Ok(access_token.token.clone())
+            }
+            _ => {
+                let now = Instant::now();
+                let response: TokenResponse = ureq::post(self.account.token_uri.as_str())
+                    .send_form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", &self.signed_jwt()?),
+                    ])
+                    .map_err(|e| classify(e, |_, _| false))?
+                    .into_json()
+                    .map_err(|e| MessageboxError::Notification(e.to_string()))?;
+                *cached = Some(AccessToken {
+                    token: response.access_token.clone(),
+                    expires: now + Duration::from_secs(response.expires_in),
+                });
+                Ok(response.access_token)
+            }
+        }
+    }
+
+    /// Returns JWT signed with service account key, that can be exchanged
+    /// for access token.
+    fn signed_jwt(&self) -> Result<String, MessageboxError> {
+        let iat = Utc::now().timestamp();
+        let header = json!({"alg": "RS256", "typ": "JWT"});
+        let claims = json!({
+            "iss": self.account.client_email,
+            "scope": SCOPE,
+            "aud": self.account.token_uri.as_str(),
+            "iat": iat,
+            "exp": iat + TOKEN_LIFETIME,
+        });
+        let to_sign = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let mut signature = vec![0; self.key_pair.public().modulus_len()];
+        self.key_pair
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                to_sign.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|e| MessageboxError::Notification(e.to_string()))?;
+        Ok(format!("{}.{}", to_sign, URL_SAFE_NO_PAD.encode(signature)))
+    }
+}
+
+impl NotificationBackend for FcmClient {
+    fn send(&self, token: &str, notification: &Notification) -> Result<(), MessageboxError> {
+        let content = notification.data();
+        // FCM accepts only string values in data.
+        let mut data: Map<String, Value> = content
+            .iter()
+            .map(|(key, value)| match value {
+                Value::String(_) => (key.clone(), value.clone()),
+                _ => (key.clone(), json!(value.to_string())),
+            })
+            .collect();
+        data.insert("click_action".into(), json!("FLUTTER_NOTIFICATION_CLICK"));
+        data.insert("id".into(), json!("1"));
+        data.insert("status".into(), json!("done"));
+        let mut message = json!({
+            "token": token,
+            "data": data,
+            "android": {
+                "priority": "high",
+            },
+        });
+        match &notification.title {
+            Some(title) => {
+                message["notification"] = json!({
+                    "title": title,
+                    "body": BODY,
+                });
+            }
+            // Data-only message, that iOS delivers only with background
+            // push type.
+            None => {
+                message["apns"] = json!({
+                    "headers": {
+                        "apns-push-type": "background",
+                        "apns-priority": "5",
+                    },
+                    "payload": {
+                        "aps": {"content-available": 1},
+                    },
+                });
+            }
+        };
+        let body = json!({ "message": message });
+        ureq::post(self.send_url.as_str())
+            .set("Authorization", &format!("Bearer {}", self.access_token()?))
+            .set("Content-Type", "application/json; charset=UTF-8")
+            .send_json(body)
+            // FCM answers 404 UNREGISTERED to tokens of uninstalled apps.
+            .map_err(|e| classify(e, |code, body| code == 404 || body.contains("UNREGISTERED")))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1110 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::MessageboxError;
+
+use self::delivery::{deliver, NotifierCounters};
+
+mod delivery;
+mod fcm;
+mod outgoing;
+mod preferences;
+mod registrations;
+mod webhook;
+mod webpush;
+
+pub use self::{
+    delivery::{DeliveryStats, RetryPolicy},
+    fcm::{FcmClient, FcmConfig},
+    outgoing::{OutgoingWebhook, WebhookDelivery, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    preferences::{Notification, NotificationPreferences, DEFAULT_TITLE},
+    registrations::{
+        default_label, InMemoryRegistrations, Registration, RegistrationStore, SledRegistrations,
+        Target, DEFAULT_LABEL,
+    },
+    webhook::{WebhookBackend, WebhookConfig},
+    webpush::{WebPushClient, WebPushConfig, WebPushKeys, WebPushSubscription},
+};
+
+/// Service that delivers notifications to devices.
+pub trait NotificationBackend: Send + Sync {
+    /// Sends `notification` about new message to device with registration
+    /// `token`. Blocks until notification is sent.
+    fn send(&self, token: &str, notification: &Notification) -> Result<(), MessageboxError>;
+}
+
+/// Backend that only logs notifications.
+pub struct NoopBackend;
+
+impl NotificationBackend for NoopBackend {
+    fn send(&self, token: &str, notification: &Notification) -> Result<(), MessageboxError> {
+        println!(
+            "Notifications disabled, not notifying token {}: {:?}",
+            token, notification
+        );
+        Ok(())
+    }
+}
+
+/// Notification backend selected in config.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotifierConfig {
+    #[default]
+    None,
+    Fcm(FcmConfig),
+    Webhook(WebhookConfig),
+}
+
+impl NotifierConfig {
+    pub fn backend(&self) -> Result<Box<dyn NotificationBackend>, MessageboxError> {
+        Ok(match self {
+            NotifierConfig::None => Box::new(NoopBackend),
+            NotifierConfig::Fcm(config) => Box::new(FcmClient::load(config)?),
+            NotifierConfig::Webhook(config) => Box::new(WebhookBackend::new(config)),
+        })
+    }
+}
+
+/// Notifier settings.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NotifyOptions {
+    /// How long registrations are valid, in seconds. Devices need to
+    /// register again after that time. Registrations never expire if not set.
+    pub registration_max_age: Option<u64>,
+    /// How failed notifications are retried.
+    #[serde(default)]
+    pub notification_retry: RetryPolicy,
+    /// Time in milliseconds, during which notifications about new messages
+    /// for identifier are merged into one. Each message is notified
+    /// separately if 0.
+    #[serde(default)]
+    pub notification_debounce: u64,
+    /// Hosts, to which outgoing webhooks can be sent over http and even if
+    /// they resolve to private addresses.
+    #[serde(default)]
+    pub webhook_allowed_hosts: Vec<String>,
+}
+
+/// New messages of identifier, that weren't notified yet.
+struct Pending {
+    /// Digest of the latest message.
+    digest: String,
+    /// Index of the latest message.
+    sn: usize,
+    /// Number of messages.
+    count: usize,
+}
+
+pub enum NotifyMessage {
+    Notify {
+        identifier: String,
+        digest: String,
+        sn: usize,
+    },
+    /// Sends notification merging pending messages of identifier, when
+    /// debounce window ends.
+    Flush { identifier: String },
+    Register {
+        identifier: String,
+        label: String,
+        target: Target,
+        sender: oneshot::Sender<Result<(), MessageboxError>>,
+    },
+    SetPreferences {
+        identifier: String,
+        preferences: NotificationPreferences,
+        sender: oneshot::Sender<Result<(), MessageboxError>>,
+    },
+    List {
+        identifier: String,
+        sender: oneshot::Sender<Result<Vec<Registration>, MessageboxError>>,
+    },
+    Remove {
+        identifier: String,
+        label: String,
+        sender: oneshot::Sender<Result<(), MessageboxError>>,
+    },
+    /// Removes registrations with `target`, that push service reported as
+    /// invalid.
+    RemoveTarget { identifier: String, target: Target },
+    SetWebhook {
+        identifier: String,
+        webhook: Option<OutgoingWebhook>,
+        sender: oneshot::Sender<Result<(), MessageboxError>>,
+    },
+    ListDeliveries {
+        identifier: String,
+        sender: oneshot::Sender<Result<Vec<WebhookDelivery>, MessageboxError>>,
+    },
+    /// Adds outcome of delivery to outgoing webhook to log of identifier.
+    LogDelivery {
+        identifier: String,
+        delivery: WebhookDelivery,
+    },
+}
+
+pub struct NotifyActor {
+    // From where get messages
+    receiver: mpsc::Receiver<NotifyMessage>,
+    backend: Arc<dyn NotificationBackend>,
+    web_push: Option<Arc<WebPushClient>>,
+    registrations: Box<dyn RegistrationStore>,
+    // How long registrations are valid
+    max_age: Option<Duration>,
+    retry: Arc<RetryPolicy>,
+    counters: Arc<NotifierCounters>,
+    // Hosts to which webhooks can be sent, even if they aren't public
+    webhook_allowed_hosts: Arc<Vec<String>>,
+    // Used by delivery tasks to report invalid targets and by debounce
+    // timers
+    sender: mpsc::WeakSender<NotifyMessage>,
+    debounce: Duration,
+    // Messages waiting for the end of debounce window, by identifier
+    pending: HashMap<String, Pending>,
+}
+
+impl NotifyActor {
+    fn new(
+        receiver: mpsc::Receiver<NotifyMessage>,
+        sender: mpsc::WeakSender<NotifyMessage>,
+        backend: Box<dyn NotificationBackend>,
+        web_push: Option<Arc<WebPushClient>>,
+        registrations: Box<dyn RegistrationStore>,
+        options: NotifyOptions,
+        counters: Arc<NotifierCounters>,
+    ) -> Self {
+        NotifyActor {
+            receiver,
+            backend: Arc::from(backend),
+            web_push,
+            registrations,
+            max_age: options.registration_max_age.map(Duration::from_secs),
+            retry: Arc::new(options.notification_retry),
+            counters,
+            webhook_allowed_hosts: Arc::new(options.webhook_allowed_hosts),
+            sender,
+            debounce: Duration::from_millis(options.notification_debounce),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns targets registered by `identifier`. Expired registrations
+    /// are removed.
+    fn targets(&mut self, identifier: &str) -> Result<Vec<Target>, MessageboxError> {
+        let registrations = self.registrations.registrations(identifier)?;
+        let (valid, expired): (Vec<_>, Vec<_>) =
+            registrations.into_iter().partition(|registration| {
+                match self
+                    .max_age
+                    .and_then(|max_age| chrono::Duration::from_std(max_age).ok())
+                {
+                    Some(max_age) => Utc::now() - registration.created < max_age,
+                    None => true,
+                }
+            });
+        if !expired.is_empty() {
+            println!(
+                "Removing {} expired registrations of {}",
+                expired.len(),
+                identifier
+            );
+            self.registrations.save_registrations(identifier, &valid)?;
+        };
+        Ok(valid
+            .into_iter()
+            .map(|registration| registration.target)
+            .collect())
+    }
+
+    /// Delivers notification to `target` in background task. Failed
+    /// deliveries are retried, and target is removed if push service reports
+    /// it as invalid.
+    fn send(&self, target: Target, identifier: String, notification: Notification) {
+        let retry = self.retry.clone();
+        let counters = self.counters.clone();
+        let sender = self.sender.clone();
+        let backend = self.backend.clone();
+        let web_push = self.web_push.clone();
+        tokio::spawn(async move {
+            let result = match &target {
+                Target::Token { token } => {
+                    let (token, notification) = (token.clone(), notification.clone());
+                    deliver(
+                        move || backend.send(&token, &notification),
+                        &retry,
+                        &counters.notifications,
+                    )
+                    .await
+                }
+                Target::WebPush { subscription } => match web_push {
+                    Some(web_push) => {
+                        let (subscription, notification) =
+                            (subscription.clone(), notification.clone());
+                        deliver(
+                            move || web_push.send(&subscription, &notification),
+                            &retry,
+                            &counters.notifications,
+                        )
+                        .await
+                    }
+                    None => {
+                        println!("Web Push not configured, not notifying {}", identifier);
+                        return;
+                    }
+                },
+            };
+            match result {
+                Ok(()) => println!("Notified device of {}", identifier),
+                Err(MessageboxError::InvalidNotificationTarget(e)) => {
+                    println!("Removing invalid device of {}: {}", identifier, e);
+                    if let Some(sender) = sender.upgrade() {
+                        let _ = sender
+                            .send(NotifyMessage::RemoveTarget { identifier, target })
+                            .await;
+                    }
+                }
+                Err(e) => println!("Can't notify device of {}: {}", identifier, e),
+            }
+        });
+    }
+
+    /// Notifies all devices of `identifier` about `pending` messages, as set
+    /// in its preferences.
+    fn notify(&mut self, identifier: &str, pending: &Pending) -> Result<(), MessageboxError> {
+        let notification = self.registrations.preferences(identifier)?.notification(
+            identifier,
+            &pending.digest,
+            pending.sn,
+            pending.count,
+        );
+        for target in self.targets(identifier)? {
+            self.send(target, identifier.to_string(), notification.clone());
+        }
+        Ok(())
+    }
+
+    /// Merges message with `digest` and index `sn` into pending messages of
+    /// `identifier`. The first message starts debounce window, after which
+    /// all of them are notified at once.
+    fn debounce(&mut self, identifier: String, digest: String, sn: usize) {
+        match self.pending.get_mut(&identifier) {
+            Some(pending) => {
+                pending.digest = digest;
+                pending.sn = sn;
+                pending.count += 1;
+            }
+            None => {
+                self.pending.insert(
+                    identifier.clone(),
+                    Pending {
+                        digest,
+                        sn,
+                        count: 1,
+                    },
+                );
+                let sender = self.sender.clone();
+                let window = self.debounce;
+                tokio::spawn(async move {
+                    tokio::time::sleep(window).await;
+                    if let Some(sender) = sender.upgrade() {
+                        let _ = sender.send(NotifyMessage::Flush { identifier }).await;
+                    }
+                });
+            }
+        }
+    }
+
+    /// Saves `target` of device of `identifier`. It replaces target
+    /// previously registered for device with the same `label`.
+    fn register(
+        &mut self,
+        identifier: &str,
+        label: String,
+        target: Target,
+    ) -> Result<(), MessageboxError> {
+        println!("Registering device {} of {}", label, identifier);
+        let mut registrations = self.registrations.registrations(identifier)?;
+        registrations.retain(|registration| registration.label != label);
+        registrations.push(Registration {
+            label,
+            target,
+            created: Utc::now(),
+        });
+        self.registrations
+            .save_registrations(identifier, &registrations)
+    }
+
+    /// Removes device of `identifier` with `label`.
+    fn remove(&mut self, identifier: &str, label: &str) -> Result<(), MessageboxError> {
+        println!("Removing device {} of {}", label, identifier);
+        let mut registrations = self.registrations.registrations(identifier)?;
+        registrations.retain(|registration| registration.label != label);
+        self.registrations
+            .save_registrations(identifier, &registrations)
+    }
+
+    /// POSTs event about message with `digest` saved at index `sn` to
+    /// outgoing webhook of `identifier`, if it is registered. Delivery is
+    /// retried in background task and its outcome is logged.
+    fn send_event(&self, identifier: &str, digest: &str, sn: usize) -> Result<(), MessageboxError> {
+        let webhook = match self.registrations.webhook(identifier)? {
+            Some(webhook) => webhook,
+            None => return Ok(()),
+        };
+        let body = OutgoingWebhook::event(identifier, digest, sn);
+        let retry = self.retry.clone();
+        let counters = self.counters.clone();
+        let allowed_hosts = self.webhook_allowed_hosts.clone();
+        let sender = self.sender.clone();
+        let identifier = identifier.to_string();
+        let digest = digest.to_string();
+        tokio::spawn(async move {
+            let result = deliver(
+                move || webhook.post(&body, &allowed_hosts),
+                &retry,
+                &counters.webhooks,
+            )
+            .await;
+            let delivery = WebhookDelivery {
+                digest,
+                sn,
+                time: Utc::now(),
+                delivered: result.is_ok(),
+                error: result.err().map(|e| e.to_string()),
+            };
+            if let Some(sender) = sender.upgrade() {
+                let _ = sender
+                    .send(NotifyMessage::LogDelivery {
+                        identifier,
+                        delivery,
+                    })
+                    .await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Sets outgoing webhook of `identifier`, if its URL is allowed.
+    fn save_webhook(
+        &mut self,
+        identifier: &str,
+        webhook: Option<OutgoingWebhook>,
+    ) -> Result<(), MessageboxError> {
+        if let Some(webhook) = &webhook {
+            webhook.check_url(&self.webhook_allowed_hosts)?;
+        };
+        self.registrations
+            .save_webhook(identifier, webhook.as_ref())
+    }
+
+    /// Adds `delivery` to log of `identifier`, keeping only the latest
+    /// deliveries.
+    fn log_delivery(
+        &mut self,
+        identifier: &str,
+        delivery: WebhookDelivery,
+    ) -> Result<(), MessageboxError> {
+        let mut deliveries = self.registrations.deliveries(identifier)?;
+        deliveries.push(delivery);
+        let excess = deliveries.len().saturating_sub(outgoing::MAX_DELIVERIES);
+        self.registrations
+            .save_deliveries(identifier, &deliveries[excess..])
+    }
+
+    /// Removes devices of `identifier` registered with `target`.
+    fn remove_target(&mut self, identifier: &str, target: &Target) -> Result<(), MessageboxError> {
+        let mut registrations = self.registrations.registrations(identifier)?;
+        let count = registrations.len();
+        registrations.retain(|registration| &registration.target != target);
+        if registrations.len() < count {
+            self.counters.notifications.removed();
+            self.registrations
+                .save_registrations(identifier, &registrations)?;
+        };
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: NotifyMessage) {
+        match msg {
+            NotifyMessage::Notify {
+                identifier,
+                digest,
+                sn,
+            } => {
+                // Events are not debounced, machines want every message.
+                if let Err(e) = self.send_event(&identifier, &digest, sn) {
+                    println!("Can't get webhook of {}: {}", identifier, e);
+                };
+                if self.debounce.is_zero() {
+                    let pending = Pending {
+                        digest,
+                        sn,
+                        count: 1,
+                    };
+                    if let Err(e) = self.notify(&identifier, &pending) {
+                        println!("Can't get registrations of {}: {}", identifier, e);
+                    };
+                } else {
+                    self.debounce(identifier, digest, sn);
+                }
+            }
+            NotifyMessage::Flush { identifier } => {
+                if let Some(pending) = self.pending.remove(&identifier) {
+                    if let Err(e) = self.notify(&identifier, &pending) {
+                        println!("Can't get registrations of {}: {}", identifier, e);
+                    };
+                }
+            }
+            NotifyMessage::SetPreferences {
+                identifier,
+                preferences,
+                sender,
+            } => {
+                println!("Setting notification preferences of {}", identifier);
+                let _ = sender.send(
+                    self.registrations
+                        .save_preferences(&identifier, &preferences),
+                );
+            }
+            NotifyMessage::Register {
+                identifier,
+                label,
+                target,
+                sender,
+            } => {
+                let _ = sender.send(self.register(&identifier, label, target));
+            }
+            NotifyMessage::List { identifier, sender } => {
+                // Remove expired registrations first.
+                let registrations = self
+                    .targets(&identifier)
+                    .and_then(|_| self.registrations.registrations(&identifier));
+                let _ = sender.send(registrations);
+            }
+            NotifyMessage::Remove {
+                identifier,
+                label,
+                sender,
+            } => {
+                let _ = sender.send(self.remove(&identifier, &label));
+            }
+            NotifyMessage::RemoveTarget { identifier, target } => {
+                if let Err(e) = self.remove_target(&identifier, &target) {
+                    println!("Can't remove device of {}: {}", identifier, e);
+                };
+            }
+            NotifyMessage::SetWebhook {
+                identifier,
+                webhook,
+                sender,
+            } => {
+                println!("Setting webhook of {}", identifier);
+                let _ = sender.send(self.save_webhook(&identifier, webhook));
+            }
+            NotifyMessage::ListDeliveries { identifier, sender } => {
+                let _ = sender.send(self.registrations.deliveries(&identifier));
+            }
+            NotifyMessage::LogDelivery {
+                identifier,
+                delivery,
+            } => {
+                if let Err(e) = self.log_delivery(&identifier, delivery) {
+                    println!("Can't log delivery to webhook of {}: {}", identifier, e);
+                };
+            }
+        }
+    }
+}
+
+async fn run_my_actor(mut actor: NotifyActor) {
+    while let Some(msg) = actor.receiver.recv().await {
+        actor.handle_message(msg).await;
+    }
+}
+
+#[derive(Clone)]
+pub struct NotifyHandle {
+    notify_sender: mpsc::Sender<NotifyMessage>,
+    counters: Arc<NotifierCounters>,
+    web_push: Option<Arc<WebPushClient>>,
+}
+
+impl NotifyHandle {
+    /// Setup notifier. Registrations older than configured max age are
+    /// removed, if set.
+    pub fn new(
+        backend: Box<dyn NotificationBackend>,
+        web_push: Option<WebPushClient>,
+        registrations: Box<dyn RegistrationStore>,
+        options: NotifyOptions,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(8);
+        let counters = Arc::new(NotifierCounters::default());
+        let web_push = web_push.map(Arc::new);
+        let actor = NotifyActor::new(
+            receiver,
+            sender.downgrade(),
+            backend,
+            web_push.clone(),
+            registrations,
+            options,
+            counters.clone(),
+        );
+        tokio::spawn(run_my_actor(actor));
+
+        Self {
+            notify_sender: sender,
+            counters,
+            web_push,
+        }
+    }
+
+    /// Returns number of notifications by delivery outcome, since start.
+    pub fn stats(&self) -> DeliveryStats {
+        self.counters.notifications.stats()
+    }
+
+    /// Returns number of events POSTed to outgoing webhooks by delivery
+    /// outcome, since start.
+    pub fn webhook_stats(&self) -> DeliveryStats {
+        self.counters.webhooks.stats()
+    }
+
+    /// Notifies devices of `identifier` about new message with `digest`,
+    /// saved at index `sn`.
+    pub async fn notify(&self, identifier: String, digest: String, sn: usize) {
+        let msg = NotifyMessage::Notify {
+            identifier,
+            digest,
+            sn,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.notify_sender.send(msg).await;
+    }
+
+    /// Registers device `token` of `identifier`.
+    pub async fn save_token(
+        &self,
+        identifier: String,
+        label: String,
+        token: String,
+    ) -> Result<(), MessageboxError> {
+        self.register(identifier, label, Target::Token { token })
+            .await
+    }
+
+    /// Registers Web Push `subscription` of `identifier`. Returns error if
+    /// push service isn't reachable with https at public address.
+    pub async fn save_subscription(
+        &self,
+        identifier: String,
+        label: String,
+        subscription: WebPushSubscription,
+    ) -> Result<(), MessageboxError> {
+        let allowed_hosts = self
+            .web_push
+            .as_ref()
+            .map(|web_push| web_push.allowed_hosts.clone())
+            .unwrap_or_default();
+        let endpoint = subscription.clone();
+        tokio::task::spawn_blocking(move || endpoint.resolve(&allowed_hosts))
+            .await
+            .unwrap_or_else(|e| Err(MessageboxError::Notification(e.to_string())))?;
+        self.register(identifier, label, Target::WebPush { subscription })
+            .await
+    }
+
+    async fn register(
+        &self,
+        identifier: String,
+        label: String,
+        target: Target,
+    ) -> Result<(), MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = NotifyMessage::Register {
+            identifier,
+            label,
+            target,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.notify_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Sets how `identifier` is notified about new messages.
+    pub async fn save_preferences(
+        &self,
+        identifier: String,
+        preferences: NotificationPreferences,
+    ) -> Result<(), MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = NotifyMessage::SetPreferences {
+            identifier,
+            preferences,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.notify_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Sets URL to which events about new messages of `identifier` are
+    /// POSTed. Removes it if `webhook` is `None`. Returns error if URL isn't
+    /// https or its host isn't allowed.
+    pub async fn save_webhook(
+        &self,
+        identifier: String,
+        webhook: Option<OutgoingWebhook>,
+    ) -> Result<(), MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = NotifyMessage::SetWebhook {
+            identifier,
+            webhook,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.notify_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Returns the latest deliveries to outgoing webhook of `identifier`.
+    pub async fn deliveries(
+        &self,
+        identifier: String,
+    ) -> Result<Vec<WebhookDelivery>, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = NotifyMessage::ListDeliveries {
+            identifier,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.notify_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Returns devices registered by `identifier`.
+    pub async fn registrations(
+        &self,
+        identifier: String,
+    ) -> Result<Vec<Registration>, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = NotifyMessage::List {
+            identifier,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.notify_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Removes device of `identifier` with `label`.
+    pub async fn remove_registration(
+        &self,
+        identifier: String,
+        label: String,
+    ) -> Result<(), MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = NotifyMessage::Remove {
+            identifier,
+            label,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.notify_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+}
+
+/// Returns DER encoded key from PEM.
+fn decode_pem(pem: &str) -> Result<Vec<u8>, MessageboxError> {
+    let encoded: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| MessageboxError::Notification(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use serde_json::{json, Value};
+
+    use crate::MessageboxError;
+
+    use super::{
+        DeliveryStats, InMemoryRegistrations, Notification, NotificationBackend,
+        NotificationPreferences, NotifyHandle, NotifyOptions, OutgoingWebhook, Registration,
+        RegistrationStore, RetryPolicy, WebhookDelivery,
+    };
+
+    /// Backend that remembers sent notifications.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<(String, Notification)>>>);
+
+    impl NotificationBackend for Recorder {
+        fn send(&self, token: &str, notification: &Notification) -> Result<(), MessageboxError> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((token.to_string(), notification.clone()));
+            Ok(())
+        }
+    }
+
+    /// Store, that can't save anything.
+    struct ReadOnly;
+
+    impl RegistrationStore for ReadOnly {
+        fn registrations(&self, _key: &str) -> Result<Vec<Registration>, MessageboxError> {
+            Ok(vec![])
+        }
+
+        fn save_registrations(
+            &mut self,
+            _key: &str,
+            _registrations: &[Registration],
+        ) -> Result<(), MessageboxError> {
+            Err(MessageboxError::Storage("read only".into()))
+        }
+
+        fn preferences(&self, _key: &str) -> Result<NotificationPreferences, MessageboxError> {
+            Ok(NotificationPreferences::default())
+        }
+
+        fn save_preferences(
+            &mut self,
+            _key: &str,
+            _preferences: &NotificationPreferences,
+        ) -> Result<(), MessageboxError> {
+            Err(MessageboxError::Storage("read only".into()))
+        }
+
+        fn webhook(&self, _key: &str) -> Result<Option<OutgoingWebhook>, MessageboxError> {
+            Ok(None)
+        }
+
+        fn save_webhook(
+            &mut self,
+            _key: &str,
+            _webhook: Option<&OutgoingWebhook>,
+        ) -> Result<(), MessageboxError> {
+            Err(MessageboxError::Storage("read only".into()))
+        }
+
+        fn deliveries(&self, _key: &str) -> Result<Vec<WebhookDelivery>, MessageboxError> {
+            Ok(vec![])
+        }
+
+        fn save_deliveries(
+            &mut self,
+            _key: &str,
+            _deliveries: &[WebhookDelivery],
+        ) -> Result<(), MessageboxError> {
+            Err(MessageboxError::Storage("read only".into()))
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_save_errors() {
+        // Errors of store are returned, so client knows it wasn't saved.
+        let notifier = NotifyHandle::new(
+            Box::new(Recorder::default()),
+            None,
+            Box::new(ReadOnly),
+            NotifyOptions::default(),
+        );
+        assert!(matches!(
+            notifier
+                .save_token("Identifier".into(), "phone".into(), "token0".into())
+                .await,
+            Err(MessageboxError::Storage(_))
+        ));
+        assert!(matches!(
+            notifier
+                .save_preferences("Identifier".into(), NotificationPreferences::default())
+                .await,
+            Err(MessageboxError::Storage(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_registrations() -> Result<(), MessageboxError> {
+        let recorder = Recorder::default();
+        let notifier = NotifyHandle::new(
+            Box::new(recorder.clone()),
+            None,
+            Box::new(InMemoryRegistrations::default()),
+            NotifyOptions::default(),
+        );
+        let notifications = || std::mem::take(&mut *recorder.0.lock().unwrap());
+        let id = || "Identifier".to_string();
+        let notification =
+            |digest, sn| NotificationPreferences::default().notification(&id(), digest, sn, 1);
+
+        notifier.notify(id(), "d0".into(), 0).await;
+        notifier
+            .save_token(id(), "phone".into(), "token0".into())
+            .await?;
+        notifier
+            .save_token(id(), "tablet".into(), "token1".into())
+            .await?;
+        // New token of device replaces the old one.
+        notifier
+            .save_token(id(), "phone".into(), "token2".into())
+            .await?;
+        let labels: Vec<_> = notifier
+            .registrations(id())
+            .await?
+            .into_iter()
+            .map(|registration| registration.label)
+            .collect();
+        assert_eq!(labels, vec!["tablet", "phone"]);
+
+        // All devices are notified.
+        notifier.notify(id(), "d1".into(), 1).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut sent = notifications();
+        sent.sort();
+        assert_eq!(
+            sent,
+            vec![
+                ("token1".into(), notification("d1", 1)),
+                ("token2".into(), notification("d1", 1))
+            ]
+        );
+
+        notifier.remove_registration(id(), "tablet".into()).await?;
+        notifier.notify(id(), "d2".into(), 2).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            notifications(),
+            vec![("token2".into(), notification("d2", 2))]
+        );
+
+        // Expired registrations are not notified.
+        let recorder = Recorder::default();
+        let notifier = NotifyHandle::new(
+            Box::new(recorder.clone()),
+            None,
+            Box::new(InMemoryRegistrations::default()),
+            NotifyOptions {
+                registration_max_age: Some(0),
+                ..Default::default()
+            },
+        );
+        notifier
+            .save_token(id(), "phone".into(), "token0".into())
+            .await?;
+        notifier.notify(id(), "d0".into(), 0).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(recorder.0.lock().unwrap().is_empty());
+        assert!(notifier.registrations(id()).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_preferences() -> Result<(), MessageboxError> {
+        let recorder = Recorder::default();
+        let notifier = NotifyHandle::new(
+            Box::new(recorder.clone()),
+            None,
+            Box::new(InMemoryRegistrations::default()),
+            NotifyOptions::default(),
+        );
+        let notifications = || std::mem::take(&mut *recorder.0.lock().unwrap());
+        let id = || "Identifier".to_string();
+        notifier
+            .save_token(id(), "phone".into(), "token0".into())
+            .await?;
+
+        // Shown notification with custom title, without identifier.
+        let preferences = NotificationPreferences {
+            title: Some("New message".into()),
+            identifier: false,
+            ..Default::default()
+        };
+        notifier.save_preferences(id(), preferences).await?;
+        notifier.notify(id(), "d0".into(), 0).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            notifications(),
+            vec![(
+                "token0".into(),
+                Notification {
+                    title: Some("New message".into()),
+                    identifier: None,
+                    digest: Some("d0".into()),
+                    sn: 0,
+                    count: 1,
+                }
+            )]
+        );
+
+        // Silent notification without any data.
+        let preferences = NotificationPreferences {
+            silent: true,
+            title: Some("Ignored".into()),
+            identifier: false,
+            digest: false,
+        };
+        notifier.save_preferences(id(), preferences).await?;
+        notifier.notify(id(), "d1".into(), 1).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let sent = notifications();
+        assert_eq!(
+            sent,
+            vec![(
+                "token0".into(),
+                Notification {
+                    title: None,
+                    identifier: None,
+                    digest: None,
+                    sn: 1,
+                    count: 1,
+                }
+            )]
+        );
+        assert_eq!(Value::Object(sent[0].1.data()), json!({"n": 1, "s": 1}));
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_debounce() -> Result<(), MessageboxError> {
+        let recorder = Recorder::default();
+        let notifier = NotifyHandle::new(
+            Box::new(recorder.clone()),
+            None,
+            Box::new(InMemoryRegistrations::default()),
+            NotifyOptions {
+                notification_debounce: 200,
+                ..Default::default()
+            },
+        );
+        let notifications = || std::mem::take(&mut *recorder.0.lock().unwrap());
+        let id = || "Identifier".to_string();
+        notifier
+            .save_token(id(), "phone".into(), "token0".into())
+            .await?;
+
+        // Burst of messages is notified once, with the latest of them.
+        for sn in 0..50 {
+            notifier.notify(id(), format!("d{}", sn), sn).await;
+        }
+        notifier.notify("Other".into(), "o0".into(), 0).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(notifications().is_empty());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let expected = NotificationPreferences::default().notification(&id(), "d49", 49, 50);
+        assert_eq!(notifications(), vec![("token0".into(), expected)]);
+
+        // Next message starts new window.
+        notifier.notify(id(), "d50".into(), 50).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let expected = NotificationPreferences::default().notification(&id(), "d50", 50, 1);
+        assert_eq!(notifications(), vec![("token0".into(), expected)]);
+
+        Ok(())
+    }
+
+    /// Backend that fails with errors scripted for each token.
+    #[derive(Default)]
+    struct Flaky(Mutex<Vec<String>>);
+
+    impl NotificationBackend for Flaky {
+        fn send(&self, token: &str, _notification: &Notification) -> Result<(), MessageboxError> {
+            let mut attempts = self.0.lock().unwrap();
+            attempts.push(token.to_string());
+            let count = attempts.iter().filter(|attempt| *attempt == token).count();
+            match token {
+                "flaky" if count < 3 => Err(MessageboxError::Notification("503".into())),
+                "down" => Err(MessageboxError::Notification("503".into())),
+                "rejected" => Err(MessageboxError::NotificationRejected("400".into())),
+                "unregistered" => Err(MessageboxError::InvalidNotificationTarget("404".into())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_delivery() -> Result<(), MessageboxError> {
+        let notifier = NotifyHandle::new(
+            Box::new(Flaky::default()),
+            None,
+            Box::new(InMemoryRegistrations::default()),
+            NotifyOptions {
+                notification_retry: RetryPolicy {
+                    max_attempts: 3,
+                    initial_backoff: 1,
+                    max_backoff: 2,
+                },
+                ..Default::default()
+            },
+        );
+        let id = || "Identifier".to_string();
+        for token in ["flaky", "down", "rejected", "unregistered"] {
+            notifier
+                .save_token(id(), token.into(), token.into())
+                .await?;
+        }
+
+        notifier.notify(id(), "d0".into(), 0).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            notifier.stats(),
+            DeliveryStats {
+                // Third attempt of "flaky" succeeds.
+                delivered: 1,
+                retried: 4,
+                // "down" runs out of attempts, "rejected" isn't retried.
+                failed: 2,
+                removed: 1,
+            }
+        );
+        // Invalid token is removed.
+        let mut labels: Vec<_> = notifier
+            .registrations(id())
+            .await?
+            .into_iter()
+            .map(|registration| registration.label)
+            .collect();
+        labels.sort();
+        assert_eq!(labels, vec!["down", "flaky", "rejected"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use crate::MessageboxError;
+
+use super::delivery::classify;
+
+/// Header with HMAC-SHA256 of timestamp and request body, keyed with
+/// webhook secret.
+pub const SIGNATURE_HEADER: &str = "X-Messagebox-Signature";
+
+/// Header with time of request, in seconds since Unix epoch.
+pub const TIMESTAMP_HEADER: &str = "X-Messagebox-Timestamp";
+
+/// How long messagebox waits for webhook or push service to respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of deliveries kept in log of each identifier.
+pub(super) const MAX_DELIVERIES: usize = 100;
+
+/// URL registered by identifier, to which events about its mailbox are
+/// POSTed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutgoingWebhook {
+    pub url: Url,
+    /// Secret shared with receiver, used to sign events.
+    pub secret: String,
+}
+
+impl OutgoingWebhook {
+    /// Returns event about message with `digest` saved for `identifier` at
+    /// index `sn`.
+    pub(super) fn event(identifier: &str, digest: &str, sn: usize) -> String {
+        json!({
+            "t": "message",
+            "i": identifier,
+            "d": digest,
+            "s": sn,
+            "dt": Utc::now(),
+        })
+        .to_string()
+    }
+
+    /// Checks if events can be POSTed to webhook URL. Only https URLs are
+    /// allowed, unless host is in `allowed_hosts`.
+    pub fn check_url(&self, allowed_hosts: &[String]) -> Result<(), MessageboxError> {
+        if is_allowed(&self.url, allowed_hosts) {
+            Ok(())
+        } else {
+            Err(MessageboxError::WebhookRejected(self.url.to_string()))
+        }
+    }
+
+    /// Resolves addresses of webhook host, like `resolve`.
+    fn resolve(&self, allowed_hosts: &[String]) -> Result<Vec<SocketAddr>, MessageboxError> {
+        resolve(&self.url, allowed_hosts)?
+            .ok_or_else(|| MessageboxError::WebhookRejected(self.url.to_string()))
+    }
+
+    /// Returns value of signature header for `body` sent at `timestamp`.
+    pub fn signature(&self, timestamp: i64, body: &[u8]) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.secret.as_bytes());
+        let mut ctx = hmac::Context::with_key(&key);
+        ctx.update(format!("{}.", timestamp).as_bytes());
+        ctx.update(body);
+        let tag = ctx.sign();
+        let hex: String = tag
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("sha256={}", hex)
+    }
+
+    /// POSTs signed `body` to webhook URL. Connects only to addresses
+    /// checked by `resolve` and doesn't follow redirects. Blocks until
+    /// request is done.
+    pub fn post(&self, body: &str, allowed_hosts: &[String]) -> Result<(), MessageboxError> {
+        let agent = pinned_agent(self.resolve(allowed_hosts)?);
+        let timestamp = Utc::now().timestamp();
+        let response = agent
+            .post(self.url.as_str())
+            .set("Content-Type", "application/json")
+            .set(TIMESTAMP_HEADER, &timestamp.to_string())
+            .set(
+                SIGNATURE_HEADER,
+                &self.signature(timestamp, body.as_bytes()),
+            )
+            .send_string(body)
+            .map_err(|e| classify(e, |_, _| false))?;
+        if response.status() >= 300 {
+            return Err(MessageboxError::NotificationRejected(format!(
+                "{}: redirects are not followed",
+                response.status()
+            )));
+        };
+        Ok(())
+    }
+}
+
+/// Checks if `url` is https, or its host is in `allowed_hosts`.
+fn is_allowed(url: &Url, allowed_hosts: &[String]) -> bool {
+    match url.host_str() {
+        Some(host) if allowed_hosts.iter().any(|allowed| allowed == host) => true,
+        Some(_) => url.scheme() == "https",
+        None => false,
+    }
+}
+
+/// Resolves addresses of `url` host. Returns `None` if URL isn't https or
+/// its addresses aren't public, unless host is in `allowed_hosts`, so
+/// URLs registered by identifiers can't be used to reach messagebox's
+/// private network.
+pub(super) fn resolve(
+    url: &Url,
+    allowed_hosts: &[String],
+) -> Result<Option<Vec<SocketAddr>>, MessageboxError> {
+    if !is_allowed(url, allowed_hosts) {
+        return Ok(None);
+    };
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses: Vec<SocketAddr> = match url.host() {
+        Some(url::Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(url::Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        _ => (host, port)
+            .to_socket_addrs()
+            .map_err(|e| MessageboxError::Notification(e.to_string()))?
+            .collect(),
+    };
+    if allowed_hosts.iter().any(|allowed| allowed == host)
+        || addresses.iter().all(|address| is_public(address.ip()))
+    {
+        Ok(Some(addresses))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns agent, that connects only to `addresses` checked by `resolve`
+/// and doesn't follow redirects.
+pub(super) fn pinned_agent(addresses: Vec<SocketAddr>) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .resolver(move |_: &str| Ok(addresses.clone()))
+        .redirects(0)
+        .timeout(TIMEOUT)
+        .build()
+}
+
+/// Checks if `ip` is public internet address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space, used by carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Outcome of delivering event to outgoing webhook.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    /// Digest of message the event was about.
+    pub digest: String,
+    pub sn: usize,
+    /// Time when delivery finished.
+    pub time: DateTime<Utc>,
+    pub delivered: bool,
+    /// Why delivery failed, after all retries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::MessageboxError;
+
+    use super::OutgoingWebhook;
+
+    fn webhook(url: &str) -> OutgoingWebhook {
+        OutgoingWebhook {
+            url: url.parse().unwrap(),
+            secret: "secret".into(),
+        }
+    }
+
+    #[test]
+    fn test_private_addresses() {
+        for url in [
+            "http://example.com/events",
+            "https://127.0.0.1/events",
+            "https://10.1.2.3/events",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/events",
+            "https://[::1]/events",
+            "https://[fd00::1]/events",
+            "https://[::ffff:192.168.0.1]/events",
+            "https://localhost/events",
+        ] {
+            assert!(
+                matches!(
+                    webhook(url).resolve(&[]),
+                    Err(MessageboxError::WebhookRejected(_))
+                ),
+                "{} is allowed",
+                url
+            );
+        }
+        assert!(webhook("https://93.184.215.14/events").resolve(&[]).is_ok());
+        // Operator can allow hosts in private network.
+        let allowed = vec!["127.0.0.1".to_string()];
+        assert!(webhook("http://127.0.0.1:8080/events")
+            .resolve(&allowed)
+            .is_ok());
+        assert!(webhook("https://10.1.2.3/events")
+            .resolve(&allowed)
+            .is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Title of notifications, used when identifier didn't set its own.
+pub const DEFAULT_TITLE: &str = "Got message for you";
+
+/// How identifier wants to be notified about new messages.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationPreferences {
+    /// Send data-only notifications, that wake up application without
+    /// showing anything to user.
+    pub silent: bool,
+    /// Title of notification shown to user.
+    pub title: Option<String>,
+    /// Include digest of message (`d`).
+    pub digest: bool,
+    /// Include identifier, to which message was sent (`i`).
+    pub identifier: bool,
+}
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        Self {
+            silent: false,
+            title: None,
+            digest: true,
+            identifier: true,
+        }
+    }
+}
+
+impl NotificationPreferences {
+    /// Returns content of notification about `count` new messages saved for
+    /// `identifier`, the latest with `digest` at index `sn`.
+    pub fn notification(
+        &self,
+        identifier: &str,
+        digest: &str,
+        sn: usize,
+        count: usize,
+    ) -> Notification {
+        Notification {
+            title: (!self.silent)
+                .then(|| self.title.clone().unwrap_or_else(|| DEFAULT_TITLE.into())),
+            identifier: self.identifier.then(|| identifier.to_string()),
+            digest: self.digest.then(|| digest.to_string()),
+            sn,
+            count,
+        }
+    }
+}
+
+/// Content of notification about new message, passed to push service.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Notification {
+    /// Title shown to user. Notification is data-only if not set.
+    pub title: Option<String>,
+    /// Identifier, to which message was sent.
+    pub identifier: Option<String>,
+    /// Digest of the latest saved message.
+    pub digest: Option<String>,
+    /// Index of the latest saved message.
+    pub sn: usize,
+    /// Number of new messages.
+    pub count: usize,
+}
+
+impl Notification {
+    /// Returns data passed to application: number of new messages (`n`),
+    /// index of the latest one (`s`), and fields `i` and `d` if they are
+    /// included.
+    pub fn data(&self) -> Map<String, Value> {
+        let mut data = Map::new();
+        data.insert("n".into(), json!(self.count));
+        data.insert("s".into(), json!(self.sn));
+        if let Some(digest) = &self.digest {
+            data.insert("d".into(), json!(digest));
+        };
+        if let Some(identifier) = &self.identifier {
+            data.insert("i".into(), json!(identifier));
+        };
+        data
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+
+use crate::MessageboxError;
+
+use super::{NotificationPreferences, OutgoingWebhook, WebPushSubscription, WebhookDelivery};
+
+/// Where notifications for identifier are delivered.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Target {
+    /// Token of configured notification backend, e.g. Firebase token.
+    Token { token: String },
+    /// Web Push subscription.
+    WebPush { subscription: WebPushSubscription },
+}
+
+/// Label of device, used when none is given.
+pub const DEFAULT_LABEL: &str = "default";
+
+/// Notification target registered by identifier.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    /// Label of device, unique among devices of identifier.
+    #[serde(default = "default_label")]
+    pub label: String,
+    pub target: Target,
+    /// Time when target was registered.
+    pub created: DateTime<Utc>,
+}
+
+pub fn default_label() -> String {
+    DEFAULT_LABEL.to_string()
+}
+
+/// Backend in which notification targets of identifiers are kept.
+pub trait RegistrationStore: Send {
+    /// Returns targets registered by identifier `key`.
+    fn registrations(&self, key: &str) -> Result<Vec<Registration>, MessageboxError>;
+
+    /// Replaces targets registered by identifier `key`.
+    fn save_registrations(
+        &mut self,
+        key: &str,
+        registrations: &[Registration],
+    ) -> Result<(), MessageboxError>;
+
+    /// Returns notification preferences of identifier `key`.
+    fn preferences(&self, key: &str) -> Result<NotificationPreferences, MessageboxError>;
+
+    /// Replaces notification preferences of identifier `key`.
+    fn save_preferences(
+        &mut self,
+        key: &str,
+        preferences: &NotificationPreferences,
+    ) -> Result<(), MessageboxError>;
+
+    /// Returns outgoing webhook registered by identifier `key`.
+    fn webhook(&self, key: &str) -> Result<Option<OutgoingWebhook>, MessageboxError>;
+
+    /// Replaces outgoing webhook of identifier `key`, or removes it if
+    /// `webhook` is `None`.
+    fn save_webhook(
+        &mut self,
+        key: &str,
+        webhook: Option<&OutgoingWebhook>,
+    ) -> Result<(), MessageboxError>;
+
+    /// Returns log of deliveries to outgoing webhook of identifier `key`.
+    fn deliveries(&self, key: &str) -> Result<Vec<WebhookDelivery>, MessageboxError>;
+
+    /// Replaces log of deliveries to outgoing webhook of identifier `key`.
+    fn save_deliveries(
+        &mut self,
+        key: &str,
+        deliveries: &[WebhookDelivery],
+    ) -> Result<(), MessageboxError>;
+}
+
+/// Store that keeps registrations in memory. They are lost on restart.
+#[derive(Default)]
+pub struct InMemoryRegistrations {
+    registrations: HashMap<String, Vec<Registration>>,
+    preferences: HashMap<String, NotificationPreferences>,
+    webhooks: HashMap<String, OutgoingWebhook>,
+    deliveries: HashMap<String, Vec<WebhookDelivery>>,
+}
+
+impl RegistrationStore for InMemoryRegistrations {
+    fn registrations(&self, key: &str) -> Result<Vec<Registration>, MessageboxError> {
+        Ok(self.registrations.get(key).cloned().unwrap_or_default())
+    }
+
+    fn save_registrations(
+        &mut self,
+        key: &str,
+        registrations: &[Registration],
+    ) -> Result<(), MessageboxError> {
+        if registrations.is_empty() {
+            self.registrations.remove(key);
+        } else {
+            self.registrations
+                .insert(key.to_string(), registrations.to_vec());
+        };
+        Ok(())
+    }
+
+    fn preferences(&self, key: &str) -> Result<NotificationPreferences, MessageboxError> {
+        Ok(self.preferences.get(key).cloned().unwrap_or_default())
+    }
+
+    fn save_preferences(
+        &mut self,
+        key: &str,
+        preferences: &NotificationPreferences,
+    ) -> Result<(), MessageboxError> {
+        self.preferences
+            .insert(key.to_string(), preferences.clone());
+        Ok(())
+    }
+
+    fn webhook(&self, key: &str) -> Result<Option<OutgoingWebhook>, MessageboxError> {
+        Ok(self.webhooks.get(key).cloned())
+    }
+
+    fn save_webhook(
+        &mut self,
+        key: &str,
+        webhook: Option<&OutgoingWebhook>,
+    ) -> Result<(), MessageboxError> {
+        match webhook {
+            Some(webhook) => self.webhooks.insert(key.to_string(), webhook.clone()),
+            None => self.webhooks.remove(key),
+        };
+        Ok(())
+    }
+
+    fn deliveries(&self, key: &str) -> Result<Vec<WebhookDelivery>, MessageboxError> {
+        Ok(self.deliveries.get(key).cloned().unwrap_or_default())
+    }
+
+    fn save_deliveries(
+        &mut self,
+        key: &str,
+        deliveries: &[WebhookDelivery],
+    ) -> Result<(), MessageboxError> {
+        self.deliveries.insert(key.to_string(), deliveries.to_vec());
+        Ok(())
+    }
+}
+
+/// Store that keeps registrations in sled database, so they survive
+/// restarts.
+pub struct SledRegistrations {
+    registrations: Tree,
+    preferences: Tree,
+    webhooks: Tree,
+    deliveries: Tree,
+}
+
+impl SledRegistrations {
+    /// Setup store using tree of already opened database.
+    pub fn new(db: &sled::Db) -> Result<Self, MessageboxError> {
+        Ok(Self {
+            registrations: db.open_tree("registrations")?,
+            preferences: db.open_tree("preferences")?,
+            webhooks: db.open_tree("webhooks")?,
+            deliveries: db.open_tree("deliveries")?,
+        })
+    }
+}
+
+impl RegistrationStore for SledRegistrations {
+    fn registrations(&self, key: &str) -> Result<Vec<Registration>, MessageboxError> {
+        match self.registrations.get(key)? {
+            Some(value) => {
+                serde_json::from_slice(&value).map_err(|e| MessageboxError::Storage(e.to_string()))
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    fn save_registrations(
+        &mut self,
+        key: &str,
+        registrations: &[Registration],
+    ) -> Result<(), MessageboxError> {
+        if registrations.is_empty() {
+            self.registrations.remove(key)?;
+        } else {
+            let value = serde_json::to_vec(registrations)
+                .map_err(|e| MessageboxError::Storage(e.to_string()))?;
+            self.registrations.insert(key, value)?;
+        };
+        Ok(())
+    }
+
+    fn preferences(&self, key: &str) -> Result<NotificationPreferences, MessageboxError> {
+        match self.preferences.get(key)? {
+            Some(value) => {
+                serde_json::from_slice(&value).map_err(|e| MessageboxError::Storage(e.to_string()))
+            }
+            None => Ok(NotificationPreferences::default()),
+        }
+    }
+
+    fn save_preferences(
+        &mut self,
+        key: &str,
+        preferences: &NotificationPreferences,
+    ) -> Result<(), MessageboxError> {
+        let value =
+            serde_json::to_vec(preferences).map_err(|e| MessageboxError::Storage(e.to_string()))?;
+        self.preferences.insert(key, value)?;
+        Ok(())
+    }
+
+    fn webhook(&self, key: &str) -> Result<Option<OutgoingWebhook>, MessageboxError> {
+        self.webhooks
+            .get(key)?
+            .map(|value| {
+                serde_json::from_slice(&value).map_err(|e| MessageboxError::Storage(e.to_string()))
+            })
+            .transpose()
+    }
+
+    fn save_webhook(
+        &mut self,
+        key: &str,
+        webhook: Option<&OutgoingWebhook>,
+    ) -> Result<(), MessageboxError> {
+        match webhook {
+            Some(webhook) => {
+                let value = serde_json::to_vec(webhook)
+                    .map_err(|e| MessageboxError::Storage(e.to_string()))?;
+                self.webhooks.insert(key, value)?;
+            }
+            None => {
+                self.webhooks.remove(key)?;
+            }
+        };
+        Ok(())
+    }
+
+    fn deliveries(&self, key: &str) -> Result<Vec<WebhookDelivery>, MessageboxError> {
+        match self.deliveries.get(key)? {
+            Some(value) => {
+                serde_json::from_slice(&value).map_err(|e| MessageboxError::Storage(e.to_string()))
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    fn save_deliveries(
+        &mut self,
+        key: &str,
+        deliveries: &[WebhookDelivery],
+    ) -> Result<(), MessageboxError> {
+        let value =
+            serde_json::to_vec(deliveries).map_err(|e| MessageboxError::Storage(e.to_string()))?;
+        self.deliveries.insert(key, value)?;
+        Ok(())
+    }
+}
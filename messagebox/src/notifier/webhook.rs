@@ -0,0 +1,42 @@
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+use crate::MessageboxError;
+
+use super::{delivery::classify, Notification, NotificationBackend};
+
+/// Settings of backend that passes notifications to HTTP endpoint, for
+/// example to self-hosted push gateway.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub url: Url,
+}
+
+/// Backend that POSTs notifications as JSON to configured URL.
+pub struct WebhookBackend {
+    url: Url,
+}
+
+impl WebhookBackend {
+    pub fn new(config: &WebhookConfig) -> Self {
+        Self {
+            url: config.url.clone(),
+        }
+    }
+}
+
+impl NotificationBackend for WebhookBackend {
+    fn send(&self, token: &str, notification: &Notification) -> Result<(), MessageboxError> {
+        let mut body = notification.data();
+        body.insert("token".into(), json!(token));
+        if let Some(title) = &notification.title {
+            body.insert("title".into(), json!(title));
+        };
+        ureq::post(self.url.as_str())
+            .send_json(body)
+            // Gateway answers 410 Gone to tokens it no longer knows.
+            .map_err(|e| classify(e, |code, _| code == 410))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256},
+    hkdf::{self, KeyType, Salt, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::MessageboxError;
+
+use super::{
+    decode_pem,
+    delivery::classify,
+    outgoing::{pinned_agent, resolve},
+    Notification,
+};
+
+/// Record size advertised in encrypted content header.
+const RECORD_SIZE: u32 = 4096;
+/// How long push service should keep undelivered message, in seconds.
+const TTL: u64 = 24 * 60 * 60;
+/// How long VAPID token is valid, in seconds.
+const VAPID_LIFETIME: i64 = 12 * 60 * 60;
+
+/// Web Push settings.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebPushConfig {
+    /// Path to VAPID private key, P-256 key in PKCS#8 PEM format.
+    pub vapid_key: PathBuf,
+    /// Contact to messagebox operator (`mailto:` or `https:` URL), passed to
+    /// push services.
+    pub subject: String,
+    /// Hosts of push services, that can be reached over http or in private
+    /// network, like self-hosted UnifiedPush distributor.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+/// Push subscription of user agent, as returned by `PushSubscription.toJSON()`
+/// in browsers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebPushSubscription {
+    pub endpoint: Url,
+    pub keys: WebPushKeys,
+}
+
+impl WebPushSubscription {
+    /// Resolves addresses of push service. Only https endpoints with public
+    /// addresses are allowed, unless host is in `allowed_hosts`. Blocks
+    /// until host is resolved.
+    pub fn resolve(&self, allowed_hosts: &[String]) -> Result<Vec<SocketAddr>, MessageboxError> {
+        resolve(&self.endpoint, allowed_hosts)?
+            .ok_or_else(|| MessageboxError::EndpointRejected(self.endpoint.to_string()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebPushKeys {
+    /// User agent public key, base64url encoded.
+    pub p256dh: String,
+    /// Authentication secret, base64url encoded.
+    pub auth: String,
+}
+
+/// Length of output keying material.
+struct Len(usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Client sending encrypted (RFC 8291) push messages (RFC 8030) to push
+/// services, authenticated with VAPID (RFC 8292).
+pub struct WebPushClient {
+    key_pair: EcdsaKeyPair,
+    subject: String,
+    pub(super) allowed_hosts: Vec<String>,
+    rng: SystemRandom,
+}
+
+impl WebPushClient {
+    pub fn load(config: &WebPushConfig) -> Result<Self, MessageboxError> {
+        let pem = std::fs::read_to_string(&config.vapid_key).map_err(|e| {
+            MessageboxError::Notification(format!(
+                "can't read VAPID key file {:?}: {}",
+                config.vapid_key, e
+            ))
+        })?;
+        let rng = SystemRandom::new();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &decode_pem(&pem)?, &rng)
+                .map_err(|e| MessageboxError::Notification(e.to_string()))?;
+        Ok(Self {
+            key_pair,
+            subject: config.subject.clone(),
+            allowed_hosts: config.allowed_hosts.clone(),
+            rng,
+        })
+    }
+
+    /// Returns VAPID public key, base64url encoded. User agents need it to
+    /// subscribe.
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.key_pair.public_key())
+    }
+
+    /// Sends `notification` to user agent `subscription`. Connects only to
+    /// addresses checked by `WebPushSubscription::resolve` and doesn't
+    /// follow redirects. Blocks until request is done.
+    pub fn send(
+        &self,
+        subscription: &WebPushSubscription,
+        notification: &Notification,
+    ) -> Result<(), MessageboxError> {
+        let mut payload = notification.data();
+        if let Some(title) = &notification.title {
+            payload.insert("title".into(), json!(title));
+        };
+        let payload = Value::Object(payload).to_string();
+        let body = self.encrypt(subscription, payload.as_bytes())?;
+        let agent = pinned_agent(subscription.resolve(&self.allowed_hosts)?);
+        let response = agent
+            .post(subscription.endpoint.as_str())
+            .set("Authorization", &self.vapid(&subscription.endpoint)?)
+            .set("Content-Encoding", "aes128gcm")
+            .set("Content-Type", "application/octet-stream")
+            .set("TTL", &TTL.to_string())
+            .set("Urgency", "high")
+            .send_bytes(&body)
+            // Push service answers 404 or 410 to subscriptions that expired
+            // or were unsubscribed.
+            .map_err(|e| classify(e, |code, _| code == 404 || code == 410))?;
+        if response.status() >= 300 {
+            return Err(MessageboxError::NotificationRejected(format!(
+                "{}: redirects are not followed",
+                response.status()
+            )));
+        };
+        Ok(())
+    }
+
+    /// Returns value of Authorization header with VAPID token for push
+    /// service at `endpoint`.
+    fn vapid(&self, endpoint: &Url) -> Result<String, MessageboxError> {
+        let header = json!({"typ": "JWT", "alg": "ES256"});
+        let claims = json!({
+            "aud": endpoint.origin().ascii_serialization(),
+            "exp": Utc::now().timestamp() + VAPID_LIFETIME,
+            "sub": self.subject,
+        });
+        let to_sign = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = self
+            .key_pair
+            .sign(&self.rng, to_sign.as_bytes())
+            .map_err(|e| MessageboxError::Notification(e.to_string()))?;
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            to_sign,
+            URL_SAFE_NO_PAD.encode(signature),
+            self.public_key()
+        ))
+    }
+
+    /// Encrypts `payload` for user agent `subscription`, using aes128gcm
+    /// content encoding, as a single record.
+    fn encrypt(
+        &self,
+        subscription: &WebPushSubscription,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, MessageboxError> {
+        let err = |e: ring::error::Unspecified| MessageboxError::Notification(e.to_string());
+        let decode = |value: &str| {
+            URL_SAFE_NO_PAD
+                .decode(value.trim_end_matches('='))
+                .map_err(|e| MessageboxError::InvalidNotificationTarget(e.to_string()))
+        };
+        let ua_public = decode(&subscription.keys.p256dh)?;
+        let auth_secret = decode(&subscription.keys.auth)?;
+
+        let as_private = EphemeralPrivateKey::generate(&ECDH_P256, &self.rng).map_err(err)?;
+        let as_public = as_private.compute_public_key().map_err(err)?;
+        let ecdh_secret = agreement::agree_ephemeral(
+            as_private,
+            &UnparsedPublicKey::new(&ECDH_P256, &ua_public),
+            |secret| secret.to_vec(),
+        )
+        .map_err(|_| {
+            MessageboxError::InvalidNotificationTarget("invalid p256dh key".to_string())
+        })?;
+
+        let key_info = [
+            b"WebPush: info\0".as_slice(),
+            &ua_public,
+            as_public.as_ref(),
+        ]
+        .concat();
+        let ikm = expand(
+            Salt::new(HKDF_SHA256, &auth_secret).extract(&ecdh_secret),
+            &key_info,
+            32,
+        )?;
+
+        let mut salt = [0u8; 16];
+        self.rng.fill(&mut salt).map_err(err)?;
+        let prk = Salt::new(HKDF_SHA256, &salt).extract(&ikm);
+        let cek = expand(prk.clone(), b"Content-Encoding: aes128gcm\0", 16)?;
+        let nonce = expand(prk, b"Content-Encoding: nonce\0", 12)?;
+
+        // Padding delimiter of the last record.
+        let mut record = [payload, &[2u8]].concat();
+        let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &cek).map_err(err)?);
+        key.seal_in_place_append_tag(
+            Nonce::try_assume_unique_for_key(&nonce).map_err(err)?,
+            Aad::empty(),
+            &mut record,
+        )
+        .map_err(err)?;
+
+        Ok([
+            salt.as_slice(),
+            &RECORD_SIZE.to_be_bytes(),
+            &[as_public.as_ref().len() as u8],
+            as_public.as_ref(),
+            &record,
+        ]
+        .concat())
+    }
+}
+
+/// Returns `len` bytes of output keying material derived from `prk`.
+fn expand(prk: hkdf::Prk, info: &[u8], len: usize) -> Result<Vec<u8>, MessageboxError> {
+    let err = |e: ring::error::Unspecified| MessageboxError::Notification(e.to_string());
+    let mut out = vec![0; len];
+    prk.expand(&[info], Len(len))
+        .map_err(err)?
+        .fill(&mut out)
+        .map_err(err)?;
+    Ok(out)
+}
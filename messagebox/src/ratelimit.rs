@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    storage::StorageHandle,
+    validate::{ExchangeArguments, MessageType},
+    MessageboxError,
+};
+
+/// Number of buckets kept before the least recently used ones are forgotten.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token bucket settings. Every message takes one token and `per_second`
+/// tokens are restored each second, up to `burst`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// Limits of messages processed by messagebox.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RateLimits {
+    /// Limit of messages signed by one identifier.
+    pub sender: Option<RateLimit>,
+    /// Limit of messages forwarded to one identifier.
+    pub recipient: Option<RateLimit>,
+    /// Limit of messages sent from one address, that wait for events of
+    /// their signer.
+    pub deferred: Option<RateLimit>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets of identifiers, that share one limit.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes token from bucket of identifier `key`. Returns error if bucket
+    /// is empty.
+    pub fn check(&self, key: &str) -> Result<(), MessageboxError> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), MessageboxError> {
+        let burst = self.limit.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            // Forget tenth of buckets, that weren't used for the longest
+            // time, so it's not done for every new identifier.
+            let mut used: Vec<_> = buckets.values().map(|bucket| bucket.updated).collect();
+            let (_, oldest_kept, _) = used.select_nth_unstable(MAX_BUCKETS / 10);
+            let oldest_kept = *oldest_kept;
+            buckets.retain(|_, bucket| bucket.updated >= oldest_kept);
+        };
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(MessageboxError::RateLimited(key.to_string()))
+        }
+    }
+}
+
+/// Limits of identifiers that sign messages and receive forwarded ones.
+/// It's shared by messagebox and validator, so messages processed after
+/// their signer's events were found are counted too.
+#[derive(Clone)]
+pub struct MessageLimiter {
+    sender: Option<RateLimiter>,
+    recipient: Option<RateLimiter>,
+    storage: StorageHandle,
+}
+
+impl MessageLimiter {
+    pub fn new(limits: &RateLimits, storage: StorageHandle) -> Self {
+        Self {
+            sender: limits.sender.clone().map(RateLimiter::new),
+            recipient: limits.recipient.clone().map(RateLimiter::new),
+            storage,
+        }
+    }
+
+    /// Checks if `signer` or recipient of forwarded message exceeded
+    /// its limit of messages. Recipient's limit is used only by messages,
+    /// that its mailbox accepts.
+    pub async fn check(&self, signer: &str, message: &str) -> Result<(), MessageboxError> {
+        if let Some(limiter) = &self.sender {
+            limiter.check(signer)?;
+        };
+        if let Some(limiter) = &self.recipient {
+            if let Ok(MessageType::Exn(ExchangeArguments::Fwd { i, .. })) =
+                serde_json::from_str(message)
+            {
+                if signer == i || self.storage.settings(&i).await?.accepts(signer) {
+                    limiter.check(&i)?;
+                };
+            };
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::MessageboxError;
+
+    use super::{RateLimit, RateLimiter, MAX_BUCKETS};
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 2,
+            per_second: 0.5,
+        });
+        let now = Instant::now();
+        assert!(limiter.check_at("Sender", now).is_ok());
+        assert!(limiter.check_at("Sender", now).is_ok());
+        assert!(matches!(
+            limiter.check_at("Sender", now),
+            Err(MessageboxError::RateLimited(_))
+        ));
+        // Other identifiers have their own buckets.
+        assert!(limiter.check_at("Other", now).is_ok());
+
+        // One token is restored after 2 seconds.
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at("Sender", later).is_err());
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.check_at("Sender", later).is_ok());
+        assert!(limiter.check_at("Sender", later).is_err());
+
+        // Bucket is not refilled over burst.
+        let later = now + Duration::from_secs(100);
+        assert!(limiter.check_at("Sender", later).is_ok());
+        assert!(limiter.check_at("Sender", later).is_ok());
+        assert!(limiter.check_at("Sender", later).is_err());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 1,
+            per_second: 0.001,
+        });
+        let now = Instant::now();
+        // Empty bucket of sender is kept, while it's used.
+        assert!(limiter.check_at("Sender", now).is_ok());
+        for i in 0..2 * MAX_BUCKETS {
+            let later = now + Duration::from_millis(i as u64 + 1);
+            let _ = limiter.check_at(&format!("Spray{}", i), later);
+            if i % 100 == 0 {
+                assert!(limiter.check_at("Sender", later).is_err());
+            };
+        }
+        assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+
+        // Buckets not used for the longest time are forgotten first.
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("Spray0"));
+        assert!(buckets.contains_key(&format!("Spray{}", 2 * MAX_BUCKETS - 1)));
+    }
+}
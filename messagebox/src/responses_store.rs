@@ -1,52 +1,497 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
-use keri_core::actor::prelude::SelfAddressingIdentifier;
+use chrono::{DateTime, Utc};
+use keri_core::actor::prelude::{HashFunction, HashFunctionCode, SelfAddressingIdentifier};
+use serde::{Deserialize, Serialize};
+use sled::Tree;
 use tokio::sync::{mpsc, oneshot};
 
+use crate::{random_token, MessageboxError};
+
+/// Seconds after which message waiting for verification expires, if not set
+/// in config.
+pub const DEFAULT_VERIFICATION_TIMEOUT: u64 = 300;
+
+/// Seconds after which response is removed, if not set in config.
+pub const DEFAULT_RESPONSE_TTL: u64 = 86_400;
+
+/// How often expired responses are removed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long deferred responses are kept.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ResponsesOptions {
+    /// Seconds for which message, whose signer's events are missing, waits
+    /// for verification, 300 by default.
+    pub verification_timeout: Option<u64>,
+    /// Seconds after which responses are removed, if requester didn't fetch
+    /// them, one day by default. Counted from the last status change.
+    pub response_ttl: Option<u64>,
+    /// Remove response once requester fetched it.
+    #[serde(default)]
+    pub delete_fetched_responses: bool,
+}
+
+impl ResponsesOptions {
+    fn verification_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.verification_timeout
+                .unwrap_or(DEFAULT_VERIFICATION_TIMEOUT),
+        )
+    }
+
+    fn response_ttl(&self) -> Duration {
+        Duration::from_secs(self.response_ttl.unwrap_or(DEFAULT_RESPONSE_TTL))
+    }
+}
+
+/// Returns digest under which response to `message` is kept.
+pub(crate) fn digest(message: &str) -> SelfAddressingIdentifier {
+    HashFunction::from(HashFunctionCode::Blake3_256).derive(message.as_bytes())
+}
+
+/// Returns key of response to message with `digest`, sent by `requester`.
+/// Responses to the same message sent by different identifiers are kept
+/// separately.
+fn key(requester: &str, digest: &SelfAddressingIdentifier) -> String {
+    format!("{}/{}", requester, digest)
+}
+
+/// State of message, which couldn't be verified when it was received.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ResponseStatus {
+    /// Message waits until signer's events are found.
+    Queued,
+    /// Watcher is asked for signer's events.
+    AskingWatcher,
+    /// Message was verified and processed. Contains response, if there is
+    /// any.
+    Reverified(Option<String>),
+    /// Message couldn't be verified or processed.
+    Failed(String),
+    /// Signer's events weren't found in time.
+    Expired,
+}
+
+impl ResponseStatus {
+    /// Returns true if message still waits for verification.
+    pub fn is_waiting(&self) -> bool {
+        matches!(self, ResponseStatus::Queued | ResponseStatus::AskingWatcher)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ResponseStatus::Queued => "queued",
+            ResponseStatus::AskingWatcher => "asking_watcher",
+            ResponseStatus::Reverified(_) => "reverified",
+            ResponseStatus::Failed(_) => "failed",
+            ResponseStatus::Expired => "expired",
+        }
+    }
+}
+
+/// Deferred response, together with identifier that sent the message. Only
+/// that identifier can get it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub requester: String,
+    pub status: ResponseStatus,
+    /// Time when message was received.
+    pub received: DateTime<Utc>,
+    /// Time of the last status change.
+    pub updated: DateTime<Utc>,
+    /// Digest of token returned when message was deferred. Requester, whose
+    /// events aren't known yet, shows it to get status and response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<SelfAddressingIdentifier>,
+}
+
+impl StoredResponse {
+    /// Returns true if `ttl` passed since the last status change.
+    fn is_older_than(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        (now - self.updated).to_std().unwrap_or_default() > ttl
+    }
+
+    /// Returns true if `token` is the one returned when message was
+    /// deferred.
+    pub fn has_token(&self, token: &str) -> bool {
+        self.token.as_ref() == Some(&digest(token))
+    }
+
+    /// Returns status record, that is shown to requester. It doesn't
+    /// contain the response.
+    pub fn record(&self) -> StatusRecord {
+        StatusRecord {
+            status: self.status.name(),
+            reason: match &self.status {
+                ResponseStatus::Failed(reason) => Some(reason.clone()),
+                _ => None,
+            },
+            received: self.received,
+            updated: self.updated,
+        }
+    }
+}
+
+/// Progress of deferred verification, returned by `GET
+/// /messages/{said}/status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StatusRecord {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub received: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+/// Backend in which deferred responses are kept, by requester and digest
+/// of message.
+pub trait ResponseStore: Send {
+    /// Returns response with `key`.
+    fn get(&self, key: &str) -> Result<Option<StoredResponse>, MessageboxError>;
+
+    /// Saves response with `key`, replacing previous one.
+    fn save(&mut self, key: &str, response: &StoredResponse) -> Result<(), MessageboxError>;
+
+    /// Removes response with `key`.
+    fn remove(&mut self, key: &str) -> Result<(), MessageboxError>;
+
+    /// Returns all responses, with their keys.
+    fn responses(&self) -> Result<Vec<(String, StoredResponse)>, MessageboxError>;
+
+    /// Returns responses with keys starting with `prefix`, with their keys.
+    fn responses_by_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, StoredResponse)>, MessageboxError>;
+}
+
+/// Store that keeps responses in memory. They are lost on restart.
+#[derive(Default)]
+pub struct InMemoryResponses {
+    responses: HashMap<String, StoredResponse>,
+}
+
+impl ResponseStore for InMemoryResponses {
+    fn get(&self, key: &str) -> Result<Option<StoredResponse>, MessageboxError> {
+        Ok(self.responses.get(key).cloned())
+    }
+
+    fn save(&mut self, key: &str, response: &StoredResponse) -> Result<(), MessageboxError> {
+        self.responses.insert(key.to_string(), response.clone());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), MessageboxError> {
+        self.responses.remove(key);
+        Ok(())
+    }
+
+    fn responses(&self) -> Result<Vec<(String, StoredResponse)>, MessageboxError> {
+        Ok(self
+            .responses
+            .iter()
+            .map(|(key, response)| (key.clone(), response.clone()))
+            .collect())
+    }
+
+    fn responses_by_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, StoredResponse)>, MessageboxError> {
+        Ok(self
+            .responses
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, response)| (key.clone(), response.clone()))
+            .collect())
+    }
+}
+
+/// Store that keeps responses in sled database, so they survive restarts.
+pub struct SledResponses {
+    responses: Tree,
+}
+
+impl SledResponses {
+    /// Setup store using tree of already opened database.
+    pub fn new(db: &sled::Db) -> Result<Self, MessageboxError> {
+        Ok(Self {
+            responses: db.open_tree("responses")?,
+        })
+    }
+}
+
+impl ResponseStore for SledResponses {
+    fn get(&self, key: &str) -> Result<Option<StoredResponse>, MessageboxError> {
+        self.responses
+            .get(key)?
+            .map(|value| {
+                serde_json::from_slice(&value).map_err(|e| MessageboxError::Storage(e.to_string()))
+            })
+            .transpose()
+    }
+
+    fn save(&mut self, key: &str, response: &StoredResponse) -> Result<(), MessageboxError> {
+        let value =
+            serde_json::to_vec(response).map_err(|e| MessageboxError::Storage(e.to_string()))?;
+        self.responses.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), MessageboxError> {
+        self.responses.remove(key)?;
+        Ok(())
+    }
+
+    fn responses(&self) -> Result<Vec<(String, StoredResponse)>, MessageboxError> {
+        self.responses_by_prefix("")
+    }
+
+    fn responses_by_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, StoredResponse)>, MessageboxError> {
+        self.responses
+            .scan_prefix(prefix)
+            .map(|entry| {
+                let (key, value) = entry?;
+                let response = serde_json::from_slice(&value)
+                    .map_err(|e| MessageboxError::Storage(e.to_string()))?;
+                Ok((String::from_utf8_lossy(&key).to_string(), response))
+            })
+            .collect()
+    }
+}
+
 pub enum ResponsesMessage {
     SaveMessage {
         digest: SelfAddressingIdentifier,
-        message: String,
+        requester: String,
+        status: ResponseStatus,
         // where to return result
-        sender: oneshot::Sender<u32>,
+        sender: oneshot::Sender<Result<(), MessageboxError>>,
     },
-    GetByDigest {
+    Defer {
         digest: SelfAddressingIdentifier,
-        sender: oneshot::Sender<Option<String>>,
+        requester: String,
+        sender: oneshot::Sender<Result<Option<String>, MessageboxError>>,
+    },
+    UpdateWaiting {
+        requester: String,
+        status: ResponseStatus,
     },
+    Get {
+        requester: String,
+        digest: SelfAddressingIdentifier,
+        sender: oneshot::Sender<Result<Option<StoredResponse>, MessageboxError>>,
+    },
+    Fetched {
+        requester: String,
+        digest: SelfAddressingIdentifier,
+    },
+    Sweep,
 }
 
 pub struct ResponsesActor {
     // From where get messages
     receiver: mpsc::Receiver<ResponsesMessage>,
-    responses: HashMap<SelfAddressingIdentifier, String>,
+    store: Box<dyn ResponseStore>,
+    options: ResponsesOptions,
 }
 
 impl ResponsesActor {
-    fn new(receiver: mpsc::Receiver<ResponsesMessage>) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<ResponsesMessage>,
+        store: Box<dyn ResponseStore>,
+        options: ResponsesOptions,
+    ) -> Self {
         ResponsesActor {
             receiver,
-            responses: HashMap::new(),
+            store,
+            options,
+        }
+    }
+
+    /// Marks message that waits for verification for too long as expired.
+    /// Returns true if it was marked.
+    fn expire(&self, response: &mut StoredResponse, now: DateTime<Utc>) -> bool {
+        let expired = response.status.is_waiting()
+            && (now - response.received).to_std().unwrap_or_default()
+                > self.options.verification_timeout();
+        if expired {
+            response.status = ResponseStatus::Expired;
+            response.updated = now;
+        };
+        expired
+    }
+
+    /// Sets status of response. Expired message was already dropped from
+    /// verification, so its status isn't changed anymore. Time of receiving
+    /// is kept while message waits for verification.
+    fn save(
+        &mut self,
+        digest: &SelfAddressingIdentifier,
+        requester: String,
+        status: ResponseStatus,
+    ) -> Result<(), MessageboxError> {
+        let key = key(&requester, digest);
+        let now = Utc::now();
+        let (received, token) = match self.store.get(&key)? {
+            Some(mut response) => {
+                if self.expire(&mut response, now) {
+                    return self.store.save(&key, &response);
+                };
+                match response.status {
+                    ResponseStatus::Expired => return Ok(()),
+                    // Message was sent again after it was processed, so
+                    // its verification starts anew.
+                    ResponseStatus::Reverified(_) | ResponseStatus::Failed(_) => {
+                        (now, response.token)
+                    }
+                    ResponseStatus::Queued | ResponseStatus::AskingWatcher => {
+                        (response.received, response.token)
+                    }
+                }
+            }
+            None => (now, None),
+        };
+        let response = StoredResponse {
+            requester,
+            status,
+            received,
+            updated: now,
+            token,
+        };
+        self.store.save(&key, &response)
+    }
+
+    /// Saves message with `digest`, sent by `requester`, as queued. Returns
+    /// token that lets requester get status and response, or `None` if the
+    /// same message already waits for verification.
+    fn defer(
+        &mut self,
+        digest: &SelfAddressingIdentifier,
+        requester: String,
+    ) -> Result<Option<String>, MessageboxError> {
+        let key = key(&requester, digest);
+        let now = Utc::now();
+        if let Some(mut response) = self.store.get(&key)? {
+            if !self.expire(&mut response, now) && response.status.is_waiting() {
+                return Ok(None);
+            };
+        };
+        let token = random_token()?;
+        let response = StoredResponse {
+            requester,
+            status: ResponseStatus::Queued,
+            received: now,
+            updated: now,
+            token: Some(self::digest(&token)),
+        };
+        self.store.save(&key, &response)?;
+        Ok(Some(token))
+    }
+
+    /// Sets status of all messages of `requester`, that wait for
+    /// verification.
+    fn update_waiting(
+        &mut self,
+        requester: &str,
+        status: ResponseStatus,
+    ) -> Result<(), MessageboxError> {
+        let now = Utc::now();
+        let prefix = format!("{}/", requester);
+        for (key, mut response) in self.store.responses_by_prefix(&prefix)? {
+            if response.requester == requester && response.status.is_waiting() {
+                response.status = status.clone();
+                response.updated = now;
+                self.store.save(&key, &response)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get(
+        &mut self,
+        requester: &str,
+        digest: &SelfAddressingIdentifier,
+    ) -> Result<Option<StoredResponse>, MessageboxError> {
+        let key = key(requester, digest);
+        let mut response = match self.store.get(&key)? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        if self.expire(&mut response, Utc::now()) {
+            self.store.save(&key, &response)?;
+        };
+        Ok(Some(response))
+    }
+
+    /// Expires messages that wait for verification too long and removes
+    /// responses older than TTL.
+    fn sweep(&mut self) -> Result<(), MessageboxError> {
+        let now = Utc::now();
+        let ttl = self.options.response_ttl();
+        for (key, mut response) in self.store.responses()? {
+            if self.expire(&mut response, now) {
+                self.store.save(&key, &response)?;
+            } else if !response.status.is_waiting() && response.is_older_than(ttl, now) {
+                self.store.remove(&key)?;
+            }
         }
+        Ok(())
     }
+
     async fn handle_message(&mut self, msg: ResponsesMessage) {
         match msg {
             ResponsesMessage::SaveMessage {
                 digest,
-                message,
+                requester,
+                status,
                 sender,
             } => {
-                self.responses.insert(digest, message);
+                let result = self.save(&digest, requester, status);
+                if let Err(e) = &result {
+                    println!("\nCan't save response to {}: {}", digest, e);
+                };
 
                 // The `let _ =` ignores any errors when sending.
                 //
                 // This can happen if the `select!` macro is used
                 // to cancel waiting for the response.
-                let _ = sender.send(1);
+                let _ = sender.send(result);
             }
-            ResponsesMessage::GetByDigest { digest, sender } => {
-                let res = self.responses.get(&digest).map(|d| d.to_owned());
-                let _ = sender.send(res);
+            ResponsesMessage::Defer {
+                digest,
+                requester,
+                sender,
+            } => {
+                let _ = sender.send(self.defer(&digest, requester));
+            }
+            ResponsesMessage::UpdateWaiting { requester, status } => {
+                if let Err(e) = self.update_waiting(&requester, status) {
+                    println!("\nCan't update responses of {}: {}", requester, e);
+                };
+            }
+            ResponsesMessage::Get {
+                requester,
+                digest,
+                sender,
+            } => {
+                let _ = sender.send(self.get(&requester, &digest));
+            }
+            ResponsesMessage::Fetched { requester, digest } => {
+                if self.options.delete_fetched_responses {
+                    if let Err(e) = self.store.remove(&key(&requester, &digest)) {
+                        println!("\nCan't remove response to {}: {}", digest, e);
+                    };
+                };
+            }
+            ResponsesMessage::Sweep => {
+                if let Err(e) = self.sweep() {
+                    println!("\nCan't remove expired responses: {}", e);
+                };
             }
         }
     }
@@ -58,15 +503,31 @@ async fn run_my_actor(mut actor: ResponsesActor) {
     }
 }
 
+/// Periodically asks responses actor to remove expired responses, until the
+/// actor is stopped.
+async fn run_sweeper(sender: mpsc::WeakSender<ResponsesMessage>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match sender.upgrade() {
+            Some(sender) => {
+                let _ = sender.send(ResponsesMessage::Sweep).await;
+            }
+            None => break,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ResponsesHandle {
     responder_sender: mpsc::Sender<ResponsesMessage>,
 }
 
 impl ResponsesHandle {
-    pub fn new() -> Self {
+    pub fn new(store: Box<dyn ResponseStore>, options: ResponsesOptions) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let actor = ResponsesActor::new(receiver);
+        tokio::spawn(run_sweeper(sender.downgrade()));
+        let actor = ResponsesActor::new(receiver, store, options);
         tokio::spawn(run_my_actor(actor));
 
         Self {
@@ -74,11 +535,18 @@ impl ResponsesHandle {
         }
     }
 
-    pub async fn save(&self, value: String, digest: SelfAddressingIdentifier) -> u32 {
+    /// Sets status of message with `digest`, sent by `requester`.
+    pub async fn save(
+        &self,
+        digest: SelfAddressingIdentifier,
+        requester: String,
+        status: ResponseStatus,
+    ) -> Result<(), MessageboxError> {
         let (send, recv) = oneshot::channel();
         let msg = ResponsesMessage::SaveMessage {
             digest,
-            message: value,
+            requester,
+            status,
             sender: send,
         };
 
@@ -86,12 +554,48 @@ impl ResponsesHandle {
         // recv.await below. There's no reason to check for the
         // same failure twice.
         let _ = self.responder_sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Saves message with `digest`, sent by `requester`, as queued until
+    /// its events are found. Returns token, that requester needs to show to
+    /// get status and response, while its events aren't known. If the same
+    /// message already waits, token isn't returned again.
+    pub async fn defer(
+        &self,
+        digest: SelfAddressingIdentifier,
+        requester: String,
+    ) -> Result<Option<String>, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = ResponsesMessage::Defer {
+            digest,
+            requester,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.responder_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Sets status of all messages of `requester`, that still wait for
+    /// verification.
+    pub async fn update_waiting(&self, requester: String, status: ResponseStatus) {
+        let msg = ResponsesMessage::UpdateWaiting { requester, status };
+        let _ = self.responder_sender.send(msg).await;
     }
 
-    pub async fn get_by_digest(&self, digest: SelfAddressingIdentifier) -> Option<String> {
+    /// Returns response to message with `digest`, sent by `requester`.
+    pub async fn get(
+        &self,
+        requester: String,
+        digest: SelfAddressingIdentifier,
+    ) -> Result<Option<StoredResponse>, MessageboxError> {
         let (send, recv) = oneshot::channel();
-        let msg = ResponsesMessage::GetByDigest {
+        let msg = ResponsesMessage::Get {
+            requester,
             digest,
             sender: send,
         };
@@ -100,12 +604,330 @@ impl ResponsesHandle {
         // recv.await below. There's no reason to check for the
         // same failure twice.
         let _ = self.responder_sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Informs that `requester` got response to message with `digest`. It's
+    /// removed, if store is set to delete fetched responses.
+    pub async fn fetched(&self, requester: String, digest: SelfAddressingIdentifier) {
+        let msg = ResponsesMessage::Fetched { requester, digest };
+        let _ = self.responder_sender.send(msg).await;
     }
 }
 
 impl Default for ResponsesHandle {
     fn default() -> Self {
-        Self::new()
+        Self::new(
+            Box::new(InMemoryResponses::default()),
+            ResponsesOptions::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+    use tokio::sync::mpsc;
+
+    use crate::MessageboxError;
+
+    use super::{
+        digest, InMemoryResponses, ResponseStatus, ResponsesActor, ResponsesHandle,
+        ResponsesOptions, StoredResponse,
+    };
+
+    #[actix_web::test]
+    async fn test_responses() {
+        let handle = ResponsesHandle::default();
+        let said = digest(r#"{"m":"hi there"}"#);
+        assert_eq!(
+            handle.get("Requester".into(), said.clone()).await.unwrap(),
+            None
+        );
+
+        handle
+            .save(said.clone(), "Requester".into(), ResponseStatus::Queued)
+            .await
+            .unwrap();
+        let queued = handle
+            .get("Requester".into(), said.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(queued.record().status, "queued");
+
+        // Only waiting messages of requester are updated.
+        let other = digest(r#"{"m":"other"}"#);
+        handle
+            .save(other.clone(), "Other".into(), ResponseStatus::Queued)
+            .await
+            .unwrap();
+        handle
+            .update_waiting("Requester".into(), ResponseStatus::AskingWatcher)
+            .await;
+        let response = handle
+            .get("Requester".into(), said.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status, ResponseStatus::AskingWatcher);
+        let response = handle.get("Other".into(), other).await.unwrap().unwrap();
+        assert_eq!(response.status, ResponseStatus::Queued);
+
+        let ready = ResponseStatus::Reverified(Some("response".into()));
+        handle
+            .save(said.clone(), "Requester".into(), ready.clone())
+            .await
+            .unwrap();
+        handle
+            .update_waiting("Requester".into(), ResponseStatus::Queued)
+            .await;
+        let response = handle
+            .get("Requester".into(), said.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.requester, "Requester");
+        assert_eq!(response.status, ready);
+        assert_eq!(response.received, queued.received);
+        assert!(response.updated >= queued.updated);
+        assert_eq!(response.record().status, "reverified");
+
+        // Fetched response is kept by default.
+        handle.fetched("Requester".into(), said.clone()).await;
+        assert!(handle
+            .get("Requester".into(), said)
+            .await
+            .unwrap()
+            .is_some());
+
+        let failed = digest(r#"{"m":"failed"}"#);
+        handle
+            .save(
+                failed.clone(),
+                "Requester".into(),
+                ResponseStatus::Failed("Verification failed".into()),
+            )
+            .await
+            .unwrap();
+        let record = handle
+            .get("Requester".into(), failed)
+            .await
+            .unwrap()
+            .unwrap()
+            .record();
+        assert_eq!(
+            serde_json::to_value(&record).unwrap()["reason"],
+            "Verification failed"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_requesters() {
+        // The same message sent by other identifier doesn't replace response.
+        let handle = ResponsesHandle::default();
+        let said = digest(r#"{"m":"hi there"}"#);
+        handle
+            .save(
+                said.clone(),
+                "Requester".into(),
+                ResponseStatus::Reverified(None),
+            )
+            .await
+            .unwrap();
+        handle
+            .save(said.clone(), "Other".into(), ResponseStatus::Queued)
+            .await
+            .unwrap();
+        let response = handle.get("Requester".into(), said.clone()).await.unwrap();
+        assert_eq!(response.unwrap().status, ResponseStatus::Reverified(None));
+        let response = handle.get("Other".into(), said.clone()).await.unwrap();
+        assert_eq!(response.unwrap().status, ResponseStatus::Queued);
+        assert_eq!(handle.get("Unknown".into(), said).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_defer() -> Result<(), MessageboxError> {
+        let handle = ResponsesHandle::default();
+        let said = digest(r#"{"m":"hi there"}"#);
+        let token = handle
+            .defer(said.clone(), "Requester".into())
+            .await?
+            .unwrap();
+        let get = || handle.get("Requester".into(), said.clone());
+        let response = get().await?.unwrap();
+        assert_eq!(response.status, ResponseStatus::Queued);
+        assert!(response.has_token(&token));
+        assert!(!response.has_token("other"));
+
+        // Token is returned once, while message waits.
+        assert_eq!(handle.defer(said.clone(), "Requester".into()).await?, None);
+        handle
+            .save(
+                said.clone(),
+                "Requester".into(),
+                ResponseStatus::AskingWatcher,
+            )
+            .await
+            .unwrap();
+        assert!(get().await?.unwrap().has_token(&token));
+
+        // The same message sent again after it was processed gets new token.
+        handle
+            .save(
+                said.clone(),
+                "Requester".into(),
+                ResponseStatus::Reverified(None),
+            )
+            .await
+            .unwrap();
+        let new_token = handle
+            .defer(said.clone(), "Requester".into())
+            .await?
+            .unwrap();
+        let response = get().await?.unwrap();
+        assert_eq!(response.status, ResponseStatus::Queued);
+        assert!(response.has_token(&new_token));
+        assert!(!response.has_token(&token));
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_expired_is_final() {
+        let options = ResponsesOptions {
+            verification_timeout: Some(0),
+            ..Default::default()
+        };
+        let handle = ResponsesHandle::new(Box::new(InMemoryResponses::default()), options);
+        let said = digest(r#"{"m":"hi there"}"#);
+        handle
+            .save(said.clone(), "Requester".into(), ResponseStatus::Queued)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        // Message that waited too long isn't processed anymore.
+        handle
+            .save(
+                said.clone(),
+                "Requester".into(),
+                ResponseStatus::Reverified(None),
+            )
+            .await
+            .unwrap();
+        let response = handle.get("Requester".into(), said.clone()).await.unwrap();
+        assert_eq!(response.unwrap().status, ResponseStatus::Expired);
+        handle
+            .save(said.clone(), "Requester".into(), ResponseStatus::Queued)
+            .await
+            .unwrap();
+        let response = handle.get("Requester".into(), said).await.unwrap();
+        assert_eq!(response.unwrap().status, ResponseStatus::Expired);
+    }
+
+    #[actix_web::test]
+    async fn test_sent_again() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let options = ResponsesOptions {
+            verification_timeout: Some(60),
+            ..Default::default()
+        };
+        let mut actor =
+            ResponsesActor::new(receiver, Box::new(InMemoryResponses::default()), options);
+        let said = digest(r#"{"m":"hi there"}"#);
+        let processed = StoredResponse {
+            requester: "Requester".into(),
+            status: ResponseStatus::Reverified(None),
+            received: Utc::now() - Duration::hours(2),
+            updated: Utc::now() - Duration::hours(2),
+            token: None,
+        };
+        actor
+            .store
+            .save(&super::key("Requester", &said), &processed)
+            .unwrap();
+
+        // Message processed long ago and sent again waits for verification
+        // from now on.
+        actor
+            .save(&said, "Requester".into(), ResponseStatus::Queued)
+            .unwrap();
+        let response = actor.get("Requester", &said).unwrap().unwrap();
+        assert_eq!(response.status, ResponseStatus::Queued);
+        assert!(response.received > processed.received);
+
+        // Only messages of the same requester are updated, even if its
+        // identifier starts the same.
+        actor
+            .save(&said, "Requester2".into(), ResponseStatus::Queued)
+            .unwrap();
+        actor
+            .update_waiting("Requester", ResponseStatus::AskingWatcher)
+            .unwrap();
+        let response = actor.get("Requester", &said).unwrap().unwrap();
+        assert_eq!(response.status, ResponseStatus::AskingWatcher);
+        let response = actor.get("Requester2", &said).unwrap().unwrap();
+        assert_eq!(response.status, ResponseStatus::Queued);
+    }
+
+    #[actix_web::test]
+    async fn test_delete_fetched() {
+        let options = ResponsesOptions {
+            delete_fetched_responses: true,
+            ..Default::default()
+        };
+        let handle = ResponsesHandle::new(Box::new(InMemoryResponses::default()), options);
+        let said = digest(r#"{"m":"hi there"}"#);
+        handle
+            .save(
+                said.clone(),
+                "Requester".into(),
+                ResponseStatus::Reverified(None),
+            )
+            .await
+            .unwrap();
+        handle.fetched("Requester".into(), said.clone()).await;
+        assert_eq!(handle.get("Requester".into(), said).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_sweep() {
+        let (_sender, receiver) = mpsc::channel(1);
+        let options = ResponsesOptions {
+            verification_timeout: Some(60),
+            response_ttl: Some(3600),
+            delete_fetched_responses: false,
+        };
+        let mut actor =
+            ResponsesActor::new(receiver, Box::new(InMemoryResponses::default()), options);
+        let response = |status, minutes_ago| StoredResponse {
+            requester: "Requester".into(),
+            status,
+            received: Utc::now() - Duration::minutes(minutes_ago),
+            updated: Utc::now() - Duration::minutes(minutes_ago),
+            token: None,
+        };
+        let responses = [
+            ("waiting", response(ResponseStatus::AskingWatcher, 0)),
+            ("too long", response(ResponseStatus::Queued, 2)),
+            ("fresh", response(ResponseStatus::Reverified(None), 59)),
+            ("old", response(ResponseStatus::Reverified(None), 61)),
+            ("old expired", response(ResponseStatus::Expired, 61)),
+        ];
+        for (digest, response) in &responses {
+            actor.store.save(digest, response).unwrap();
+        }
+
+        actor.sweep().unwrap();
+        let status = |digest| actor.store.get(digest).unwrap().map(|r| r.status);
+        assert_eq!(status("waiting"), Some(ResponseStatus::AskingWatcher));
+        assert_eq!(status("too long"), Some(ResponseStatus::Expired));
+        assert_eq!(status("fresh"), Some(ResponseStatus::Reverified(None)));
+        assert_eq!(status("old"), None);
+        assert_eq!(status("old expired"), None);
+
+        // Expired message is removed after TTL from expiring.
+        let expired = actor.store.get("too long").unwrap().unwrap();
+        assert!(expired.updated > expired.received);
     }
 }
@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::MessageboxError;
+
+use super::{IndexedMessages, MailboxSettings, MailboxStore, StoredMessage};
+
+#[derive(Default)]
+struct Mailbox {
+    // Index that will be assigned to the next message.
+    next_index: usize,
+    messages: BTreeMap<usize, StoredMessage>,
+    // Number of messages dropped because of retention policy.
+    dropped: usize,
+}
+
+/// Store that keeps messages in memory. They are lost on restart.
+#[derive(Default)]
+pub struct InMemoryStore {
+    mailboxes: HashMap<String, Mailbox>,
+    settings: HashMap<String, MailboxSettings>,
+}
+
+impl MailboxStore for InMemoryStore {
+    fn save(&mut self, key: &str, message: StoredMessage) -> Result<usize, MessageboxError> {
+        let mailbox = self.mailboxes.entry(key.to_string()).or_default();
+        let index = mailbox.next_index;
+        mailbox.messages.insert(index, message);
+        mailbox.next_index += 1;
+        Ok(index)
+    }
+
+    fn get_by_sn(
+        &self,
+        key: &str,
+        sn: usize,
+    ) -> Result<Option<(usize, IndexedMessages)>, MessageboxError> {
+        Ok(self
+            .mailboxes
+            .get(key)
+            .filter(|mailbox| sn <= mailbox.next_index)
+            .map(|mailbox| {
+                let messages = mailbox
+                    .messages
+                    .range(sn..)
+                    .map(|(index, msg)| (*index, msg.clone()))
+                    .collect();
+                (mailbox.next_index - 1, messages)
+            }))
+    }
+
+    fn get_by_digest(
+        &self,
+        key: &str,
+        digests: &[String],
+    ) -> Result<Option<IndexedMessages>, MessageboxError> {
+        Ok(self.mailboxes.get(key).map(|mailbox| {
+            mailbox
+                .messages
+                .iter()
+                .filter(|(_index, msg)| digests.contains(&msg.digest))
+                .map(|(index, msg)| (*index, msg.clone()))
+                .collect()
+        }))
+    }
+
+    fn oldest(&self, key: &str, limit: usize) -> Result<IndexedMessages, MessageboxError> {
+        Ok(self
+            .mailboxes
+            .get(key)
+            .map(|mailbox| {
+                mailbox
+                    .messages
+                    .iter()
+                    .take(limit)
+                    .map(|(index, msg)| (*index, msg.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn remove(&mut self, key: &str, indexes: &[usize]) -> Result<(), MessageboxError> {
+        if let Some(mailbox) = self.mailboxes.get_mut(key) {
+            for index in indexes {
+                mailbox.messages.remove(index);
+            }
+        };
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, MessageboxError> {
+        Ok(self.mailboxes.keys().cloned().collect())
+    }
+
+    fn dropped(&self, key: &str) -> Result<usize, MessageboxError> {
+        Ok(self
+            .mailboxes
+            .get(key)
+            .map(|mailbox| mailbox.dropped)
+            .unwrap_or_default())
+    }
+
+    fn add_dropped(&mut self, key: &str, count: usize) -> Result<(), MessageboxError> {
+        self.mailboxes.entry(key.to_string()).or_default().dropped += count;
+        Ok(())
+    }
+
+    fn settings(&self, key: &str) -> Result<MailboxSettings, MessageboxError> {
+        Ok(self.settings.get(key).cloned().unwrap_or_default())
+    }
+
+    fn save_settings(
+        &mut self,
+        key: &str,
+        settings: MailboxSettings,
+    ) -> Result<(), MessageboxError> {
+        self.settings.insert(key.to_string(), settings);
+        Ok(())
+    }
+}
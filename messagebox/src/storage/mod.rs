@@ -0,0 +1,612 @@
+mod memory;
+mod persistent;
+mod retention;
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{
+    notifier::{InMemoryRegistrations, NotifyHandle, RegistrationStore, SledRegistrations},
+    responses_store::{InMemoryResponses, ResponseStore, SledResponses},
+    verify::{InMemoryReverify, ReverifyStore, SledReverify},
+    MessageboxError,
+};
+
+pub use self::{memory::InMemoryStore, persistent::SledStore, retention::RetentionPolicy};
+
+pub type Message = serde_json::Value;
+
+/// Stores of data that messagebox keeps for identifiers.
+pub struct Stores {
+    pub mailbox: Box<dyn MailboxStore>,
+    pub registrations: Box<dyn RegistrationStore>,
+    pub responses: Box<dyn ResponseStore>,
+    pub reverify: Box<dyn ReverifyStore>,
+}
+
+impl Stores {
+    /// Stores that keep data in memory. It is lost on restart.
+    pub fn in_memory() -> Self {
+        Self {
+            mailbox: Box::new(InMemoryStore::default()),
+            registrations: Box::new(InMemoryRegistrations::default()),
+            responses: Box::new(InMemoryResponses::default()),
+            reverify: Box::new(InMemoryReverify::default()),
+        }
+    }
+
+    /// Stores that keep data in sled database at `path`.
+    pub fn open(path: &Path) -> Result<Self, MessageboxError> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            mailbox: Box::new(SledStore::new(&db)?),
+            registrations: Box::new(SledRegistrations::new(&db)?),
+            responses: Box::new(SledResponses::new(&db)?),
+            reverify: Box::new(SledReverify::new(&db)?),
+        })
+    }
+}
+
+/// Message forwarded to identifier, together with data that allows its
+/// recipient to verify who sent it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// Digest of forwarded data.
+    #[serde(rename = "d")]
+    pub digest: String,
+    /// Identifier that signed the `exn` message.
+    #[serde(rename = "i")]
+    pub sender: String,
+    /// Forwarded data.
+    #[serde(rename = "a")]
+    pub data: Message,
+    /// Original `exn` message with sender signatures attached, in CESR format.
+    pub exn: String,
+    /// Time when message was received by messagebox.
+    #[serde(rename = "dt")]
+    pub received: DateTime<Utc>,
+}
+
+impl StoredMessage {
+    /// Size of the message, as received, in bytes.
+    pub fn size(&self) -> usize {
+        self.exn.len()
+    }
+}
+
+/// Messages together with their indexes in mailbox.
+pub type IndexedMessages = Vec<(usize, StoredMessage)>;
+
+/// Mailbox configuration managed by its owner.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MailboxSettings {
+    /// Identifiers, other than the owner, allowed to read messages.
+    #[serde(default)]
+    pub readers: Vec<String>,
+    /// If set, only these identifiers can forward messages to the mailbox.
+    #[serde(default)]
+    pub allowed_senders: Option<Vec<String>>,
+    /// Identifiers that can't forward messages to the mailbox.
+    #[serde(default)]
+    pub blocked_senders: Vec<String>,
+}
+
+impl MailboxSettings {
+    /// Checks if messages from `sender` can be saved in the mailbox.
+    pub fn accepts(&self, sender: &str) -> bool {
+        let allowed = match &self.allowed_senders {
+            Some(allowed) => allowed.iter().any(|s| s == sender),
+            None => true,
+        };
+        allowed && !self.blocked_senders.iter().any(|s| s == sender)
+    }
+}
+
+/// Backend in which messages forwarded to identifiers are kept.
+///
+/// Every message saved for identifier gets next index, starting from 0.
+/// Indexes are used by clients to query for messages they haven't seen yet.
+pub trait MailboxStore: Send {
+    /// Saves message for identifier `key` and returns index assigned to it.
+    fn save(&mut self, key: &str, message: StoredMessage) -> Result<usize, MessageboxError>;
+
+    /// Returns index of the last message saved for identifier `key` and all
+    /// its messages, with their indexes, starting from index `sn`. Returns
+    /// `None` if no message was ever saved for `key` or `sn` is beyond the
+    /// next index.
+    fn get_by_sn(
+        &self,
+        key: &str,
+        sn: usize,
+    ) -> Result<Option<(usize, IndexedMessages)>, MessageboxError>;
+
+    /// Returns messages of identifier `key` with their indexes, which digests
+    /// are in `digests`. Returns `None` if no message was ever saved for
+    /// `key`.
+    fn get_by_digest(
+        &self,
+        key: &str,
+        digests: &[String],
+    ) -> Result<Option<IndexedMessages>, MessageboxError>;
+
+    /// Returns at most `limit` messages of identifier `key` with the lowest
+    /// indexes, together with their indexes.
+    fn oldest(&self, key: &str, limit: usize) -> Result<IndexedMessages, MessageboxError>;
+
+    /// Removes messages of identifier `key` with given indexes. Indexes of
+    /// remaining messages don't change and removed indexes aren't reused.
+    fn remove(&mut self, key: &str, indexes: &[usize]) -> Result<(), MessageboxError>;
+
+    /// Returns identifiers for which any message was ever saved.
+    fn keys(&self) -> Result<Vec<String>, MessageboxError>;
+
+    /// Returns number of messages of identifier `key` dropped because of
+    /// retention policy.
+    fn dropped(&self, key: &str) -> Result<usize, MessageboxError>;
+
+    /// Increases number of messages of identifier `key` dropped because of
+    /// retention policy.
+    fn add_dropped(&mut self, key: &str, count: usize) -> Result<(), MessageboxError>;
+
+    /// Returns settings of mailbox of identifier `key`.
+    fn settings(&self, key: &str) -> Result<MailboxSettings, MessageboxError>;
+
+    /// Replaces settings of mailbox of identifier `key`.
+    fn save_settings(
+        &mut self,
+        key: &str,
+        settings: MailboxSettings,
+    ) -> Result<(), MessageboxError>;
+}
+
+/// Number and total size of messages kept in mailbox.
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    count: usize,
+    bytes: usize,
+}
+
+/// Stored message together with its index, as returned in query responses.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    sn: usize,
+    #[serde(flatten)]
+    message: &'a StoredMessage,
+}
+
+pub enum StorageMessage {
+    SaveMessage {
+        key: String,
+        message: StoredMessage,
+        // where to return result
+        sender: oneshot::Sender<Result<u32, MessageboxError>>,
+    },
+    GetBySn {
+        key: String,
+        index: usize,
+        // where to return result
+        sender: oneshot::Sender<Result<Option<String>, MessageboxError>>,
+    },
+    GetByDigest {
+        key: String,
+        digests: Vec<String>,
+        sender: oneshot::Sender<Result<Option<String>, MessageboxError>>,
+    },
+    Remove {
+        key: String,
+        digests: Vec<String>,
+        up_to_sn: Option<usize>,
+        sender: oneshot::Sender<Result<(), MessageboxError>>,
+    },
+    // Remove messages that exceed retention limits
+    Sweep,
+    GetSettings {
+        key: String,
+        sender: oneshot::Sender<Result<MailboxSettings, MessageboxError>>,
+    },
+    SaveSettings {
+        key: String,
+        settings: MailboxSettings,
+        sender: oneshot::Sender<Result<(), MessageboxError>>,
+    },
+}
+
+pub struct StorageActor {
+    // From where get messages
+    receiver: mpsc::Receiver<StorageMessage>,
+    store: Box<dyn MailboxStore>,
+    retention: RetentionPolicy,
+    notify_handle: NotifyHandle,
+    // Where identifiers are sent after message for them is saved
+    saved: broadcast::Sender<String>,
+    // Totals of mailboxes, counted once and updated on every save, so size
+    // limits can be checked without reading the whole mailbox
+    totals: HashMap<String, Totals>,
+}
+
+impl StorageActor {
+    fn new(
+        receiver: mpsc::Receiver<StorageMessage>,
+        store: Box<dyn MailboxStore>,
+        retention: RetentionPolicy,
+        notify_handle: NotifyHandle,
+        saved: broadcast::Sender<String>,
+    ) -> Self {
+        StorageActor {
+            receiver,
+            store,
+            retention,
+            notify_handle,
+            saved,
+            totals: HashMap::new(),
+        }
+    }
+
+    /// Returns totals of mailbox of identifier `key`. They are counted from
+    /// stored messages if they are not known yet.
+    fn totals(&mut self, key: &str) -> Result<Totals, MessageboxError> {
+        if let Some(totals) = self.totals.get(key) {
+            return Ok(*totals);
+        };
+        let (_, messages) = self.store.get_by_sn(key, 0)?.unwrap_or_default();
+        let totals = Totals {
+            count: messages.len(),
+            bytes: messages.iter().map(|(_, msg)| msg.size()).sum(),
+        };
+        self.totals.insert(key.to_string(), totals);
+        Ok(totals)
+    }
+
+    /// Saves message for identifier `key`, removing the oldest messages
+    /// that exceed size limits. Returns index of saved message and true if
+    /// it is kept.
+    fn save(
+        &mut self,
+        key: &str,
+        message: StoredMessage,
+    ) -> Result<(usize, bool), MessageboxError> {
+        let size = message.size();
+        if let Some(max_bytes) = self.retention.max_bytes {
+            if size > max_bytes {
+                return Err(MessageboxError::MessageTooLarge(size, max_bytes));
+            };
+        };
+        if !self.retention.limits_size() {
+            return Ok((self.store.save(key, message)?, true));
+        };
+        let mut totals = self.totals(key)?;
+        let index = self.store.save(key, message)?;
+        totals.count += 1;
+        totals.bytes += size;
+        let mut dropped = vec![];
+        while self.retention.exceeds_size(totals.count, totals.bytes) {
+            // Usually only the oldest message needs to be dropped.
+            let excess = self
+                .retention
+                .max_messages
+                .map(|max| totals.count.saturating_sub(max))
+                .unwrap_or_default()
+                .max(1);
+            let oldest = self.store.oldest(key, excess)?;
+            if oldest.is_empty() {
+                break;
+            };
+            let mut to_drop = vec![];
+            for (index, msg) in oldest {
+                if !self.retention.exceeds_size(totals.count, totals.bytes) {
+                    break;
+                };
+                to_drop.push(index);
+                totals.count -= 1;
+                totals.bytes -= msg.size();
+            }
+            self.store.remove(key, &to_drop)?;
+            dropped.extend(to_drop);
+        }
+        self.totals.insert(key.to_string(), totals);
+        if !dropped.is_empty() {
+            println!("Dropping {} messages of {}", dropped.len(), key);
+            self.store.add_dropped(key, dropped.len())?;
+        };
+        Ok((index, !dropped.contains(&index)))
+    }
+
+    /// Removes messages of identifier `key` that exceed retention limits.
+    /// Reads the whole mailbox, so it's used only by periodic sweep.
+    fn apply_retention(&mut self, key: &str) -> Result<(), MessageboxError> {
+        let (_, messages) = self.store.get_by_sn(key, 0)?.unwrap_or_default();
+        let to_drop = self.retention.select_to_drop(&messages, Utc::now());
+        if !to_drop.is_empty() {
+            println!("Dropping {} messages of {}", to_drop.len(), key);
+            self.store.remove(key, &to_drop)?;
+            self.store.add_dropped(key, to_drop.len())?;
+            self.totals.remove(key);
+        };
+        Ok(())
+    }
+
+    fn sweep(&mut self) -> Result<(), MessageboxError> {
+        for key in self.store.keys()? {
+            self.apply_retention(&key)?;
+        }
+        Ok(())
+    }
+
+    fn get_by_sn(&self, key: &str, sn: usize) -> Result<Option<String>, MessageboxError> {
+        let (last_id, messages) = match self.store.get_by_sn(key, sn)? {
+            Some(messages) => messages,
+            None => return Ok(None),
+        };
+        let mut out = json!({"last_sn": last_id, "messages": Self::envelopes(&messages)});
+        // Let client know, that some messages were removed before they
+        // could be read.
+        let dropped = self.store.dropped(key)?;
+        if dropped > 0 {
+            out["dropped"] = json!(dropped);
+        };
+        Ok(Some(out.to_string()))
+    }
+
+    fn envelopes(messages: &[(usize, StoredMessage)]) -> Vec<Envelope<'_>> {
+        messages
+            .iter()
+            .map(|(sn, message)| Envelope { sn: *sn, message })
+            .collect()
+    }
+
+    /// Removes messages with given digests and all messages with index lower
+    /// or equal to `up_to_sn`.
+    fn remove(
+        &mut self,
+        key: &str,
+        digests: &[String],
+        up_to_sn: Option<usize>,
+    ) -> Result<(), MessageboxError> {
+        let mut indexes = self
+            .store
+            .get_by_digest(key, digests)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        if let Some(sn) = up_to_sn {
+            let (_, messages) = self.store.get_by_sn(key, 0)?.unwrap_or_default();
+            indexes.extend(
+                messages
+                    .into_iter()
+                    .map(|(index, _)| index)
+                    .take_while(|index| *index <= sn),
+            );
+        };
+        self.totals.remove(key);
+        self.store.remove(key, &indexes)
+    }
+
+    async fn handle_message(&mut self, msg: StorageMessage) {
+        match msg {
+            StorageMessage::SaveMessage {
+                key,
+                message,
+                sender,
+            } => {
+                let digest = message.digest.clone();
+                let res = self.save(&key, message);
+                // Nobody is notified about message dropped right away.
+                if let Ok((index, true)) = res {
+                    // Fails only if nobody is subscribed.
+                    let _ = self.saved.send(key.clone());
+                    self.notify_handle.notify(key, digest, index).await;
+                };
+
+                // The `let _ =` ignores any errors when sending.
+                //
+                // This can happen if the `select!` macro is used
+                // to cancel waiting for the response.
+                let _ = sender.send(res.map(|_| 1));
+            }
+            StorageMessage::GetBySn { key, sender, index } => {
+                let _ = sender.send(self.get_by_sn(&key, index));
+            }
+            StorageMessage::GetByDigest {
+                key,
+                digests,
+                sender,
+            } => {
+                let out = self.store.get_by_digest(&key, &digests).map(|messages| {
+                    messages.map(|messages| json!(Self::envelopes(&messages)).to_string())
+                });
+                let _ = sender.send(out);
+            }
+            StorageMessage::Remove {
+                key,
+                digests,
+                up_to_sn,
+                sender,
+            } => {
+                let _ = sender.send(self.remove(&key, &digests, up_to_sn));
+            }
+            StorageMessage::Sweep => {
+                if let Err(e) = self.sweep() {
+                    println!("\nCan't remove expired messages: {}", e);
+                };
+            }
+            StorageMessage::GetSettings { key, sender } => {
+                let _ = sender.send(self.store.settings(&key));
+            }
+            StorageMessage::SaveSettings {
+                key,
+                settings,
+                sender,
+            } => {
+                let _ = sender.send(self.store.save_settings(&key, settings));
+            }
+        }
+    }
+}
+
+async fn run_my_actor(mut actor: StorageActor) {
+    while let Some(msg) = actor.receiver.recv().await {
+        actor.handle_message(msg).await;
+    }
+}
+
+/// Periodically asks storage actor to remove expired messages, until the
+/// actor is stopped.
+async fn run_sweeper(sender: mpsc::WeakSender<StorageMessage>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match sender.upgrade() {
+            Some(sender) => {
+                let _ = sender.send(StorageMessage::Sweep).await;
+            }
+            None => break,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StorageHandle {
+    database_sender: mpsc::Sender<StorageMessage>,
+    saved: broadcast::Sender<String>,
+}
+
+impl StorageHandle {
+    pub fn new(
+        store: Box<dyn MailboxStore>,
+        retention: RetentionPolicy,
+        notify_handle: NotifyHandle,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(8);
+        if retention.max_age.is_some() {
+            tokio::spawn(run_sweeper(sender.downgrade(), retention.sweep_interval()));
+        };
+        let (saved, _) = broadcast::channel(64);
+        let actor = StorageActor::new(receiver, store, retention, notify_handle, saved.clone());
+        tokio::spawn(run_my_actor(actor));
+
+        Self {
+            database_sender: sender,
+            saved,
+        }
+    }
+
+    /// Returns receiver of identifiers, for which messages are saved from
+    /// now on. If receiver lags behind, some identifiers are skipped.
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.saved.subscribe()
+    }
+
+    pub async fn save(&self, key: String, message: StoredMessage) -> Result<u32, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = StorageMessage::SaveMessage {
+            key,
+            message,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.database_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    pub async fn get_by_index(
+        &self,
+        id: &str,
+        index: usize,
+    ) -> Result<Option<String>, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = StorageMessage::GetBySn {
+            key: id.to_string(),
+            index,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.database_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    pub async fn get_by_digest(
+        &self,
+        id: &str,
+        digests: Vec<String>,
+    ) -> Result<Option<String>, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = StorageMessage::GetByDigest {
+            key: id.to_string(),
+            digests,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.database_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Removes messages of identifier `id` with given digests and all its
+    /// messages with index lower or equal to `up_to_sn`.
+    pub async fn remove(
+        &self,
+        id: &str,
+        digests: Vec<String>,
+        up_to_sn: Option<usize>,
+    ) -> Result<(), MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = StorageMessage::Remove {
+            key: id.to_string(),
+            digests,
+            up_to_sn,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.database_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    pub async fn settings(&self, id: &str) -> Result<MailboxSettings, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = StorageMessage::GetSettings {
+            key: id.to_string(),
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.database_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    pub async fn save_settings(
+        &self,
+        id: &str,
+        settings: MailboxSettings,
+    ) -> Result<(), MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = StorageMessage::SaveSettings {
+            key: id.to_string(),
+            settings,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.database_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+}
@@ -0,0 +1,197 @@
+use std::path::Path;
+
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional, Tree,
+};
+
+use crate::MessageboxError;
+
+use super::{IndexedMessages, MailboxSettings, MailboxStore, StoredMessage};
+
+/// Store that keeps messages in sled database, so they survive restarts.
+pub struct SledStore {
+    // Messages of identifier, keyed by identifier and message index.
+    messages: Tree,
+    // Index that will be assigned to the next message of identifier.
+    next_indexes: Tree,
+    // Number of messages of identifier dropped because of retention policy.
+    dropped: Tree,
+    settings: Tree,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> Result<Self, MessageboxError> {
+        Self::new(&sled::open(path)?)
+    }
+
+    /// Setup store using trees of already opened database.
+    pub fn new(db: &sled::Db) -> Result<Self, MessageboxError> {
+        Ok(Self {
+            messages: db.open_tree("messages")?,
+            next_indexes: db.open_tree("next_indexes")?,
+            dropped: db.open_tree("dropped")?,
+            settings: db.open_tree("settings")?,
+        })
+    }
+
+    fn message_key(key: &str, index: u64) -> Vec<u8> {
+        let mut out = Self::prefix(key);
+        out.extend_from_slice(&index.to_be_bytes());
+        out
+    }
+
+    fn prefix(key: &str) -> Vec<u8> {
+        let mut out = key.as_bytes().to_vec();
+        out.push(0);
+        out
+    }
+
+    fn decode_index(value: &[u8]) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(value);
+        u64::from_be_bytes(bytes)
+    }
+
+    fn next_index(&self, key: &str) -> Result<Option<usize>, MessageboxError> {
+        Ok(self
+            .next_indexes
+            .get(key)?
+            .map(|value| Self::decode_index(&value) as usize))
+    }
+
+    /// Returns messages of identifier with its indexes, starting from
+    /// index `from`, ordered by index.
+    fn messages(&self, key: &str, from: usize) -> Result<IndexedMessages, MessageboxError> {
+        self.iter_messages(key, from).collect()
+    }
+
+    /// Iterates over messages of identifier with its indexes, starting from
+    /// index `from`, in order of index. Messages are read lazily.
+    fn iter_messages(
+        &self,
+        key: &str,
+        from: usize,
+    ) -> impl Iterator<Item = Result<(usize, StoredMessage), MessageboxError>> {
+        let prefix = Self::prefix(key);
+        self.messages
+            .range(Self::message_key(key, from as u64)..)
+            .take_while(move |entry| {
+                entry
+                    .as_ref()
+                    .map(|(k, _)| k.starts_with(&prefix))
+                    .unwrap_or(true)
+            })
+            .map(|entry| {
+                let (k, value) = entry?;
+                let index = Self::decode_index(&k[k.len() - 8..]) as usize;
+                serde_json::from_slice(&value)
+                    .map(|msg| (index, msg))
+                    .map_err(|e| MessageboxError::Storage(e.to_string()))
+            })
+    }
+}
+
+impl MailboxStore for SledStore {
+    fn save(&mut self, key: &str, message: StoredMessage) -> Result<usize, MessageboxError> {
+        let value =
+            serde_json::to_vec(&message).map_err(|e| MessageboxError::Storage(e.to_string()))?;
+        (&self.messages, &self.next_indexes)
+            .transaction(|(messages, next_indexes)| {
+                let index = next_indexes
+                    .get(key)?
+                    .map(|value| Self::decode_index(&value))
+                    .unwrap_or_default();
+                messages.insert(Self::message_key(key, index), value.clone())?;
+                next_indexes.insert(key, &(index + 1).to_be_bytes())?;
+                Ok::<_, ConflictableTransactionError>(index as usize)
+            })
+            .map_err(|e: TransactionError| MessageboxError::Storage(e.to_string()))
+    }
+
+    fn get_by_sn(
+        &self,
+        key: &str,
+        sn: usize,
+    ) -> Result<Option<(usize, IndexedMessages)>, MessageboxError> {
+        match self.next_index(key)? {
+            Some(next_index) if sn <= next_index => {
+                Ok(Some((next_index - 1, self.messages(key, sn)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn get_by_digest(
+        &self,
+        key: &str,
+        digests: &[String],
+    ) -> Result<Option<IndexedMessages>, MessageboxError> {
+        if self.next_index(key)?.is_none() {
+            return Ok(None);
+        };
+        Ok(Some(
+            self.messages(key, 0)?
+                .into_iter()
+                .filter(|(_index, msg)| digests.contains(&msg.digest))
+                .collect(),
+        ))
+    }
+
+    fn oldest(&self, key: &str, limit: usize) -> Result<IndexedMessages, MessageboxError> {
+        self.iter_messages(key, 0).take(limit).collect()
+    }
+
+    fn remove(&mut self, key: &str, indexes: &[usize]) -> Result<(), MessageboxError> {
+        let mut batch = sled::Batch::default();
+        for index in indexes {
+            batch.remove(Self::message_key(key, *index as u64));
+        }
+        self.messages.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, MessageboxError> {
+        self.next_indexes
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8_lossy(&key?).to_string()))
+            .collect()
+    }
+
+    fn dropped(&self, key: &str) -> Result<usize, MessageboxError> {
+        Ok(self
+            .dropped
+            .get(key)?
+            .map(|value| Self::decode_index(&value) as usize)
+            .unwrap_or_default())
+    }
+
+    fn add_dropped(&mut self, key: &str, count: usize) -> Result<(), MessageboxError> {
+        self.dropped.update_and_fetch(key, |value| {
+            let dropped = value.map(Self::decode_index).unwrap_or_default() + count as u64;
+            Some(dropped.to_be_bytes().to_vec())
+        })?;
+        Ok(())
+    }
+
+    fn settings(&self, key: &str) -> Result<MailboxSettings, MessageboxError> {
+        match self.settings.get(key)? {
+            Some(value) => {
+                serde_json::from_slice(&value).map_err(|e| MessageboxError::Storage(e.to_string()))
+            }
+            None => Ok(MailboxSettings::default()),
+        }
+    }
+
+    fn save_settings(
+        &mut self,
+        key: &str,
+        settings: MailboxSettings,
+    ) -> Result<(), MessageboxError> {
+        let value =
+            serde_json::to_vec(&settings).map_err(|e| MessageboxError::Storage(e.to_string()))?;
+        self.settings.insert(key, value)?;
+        Ok(())
+    }
+}
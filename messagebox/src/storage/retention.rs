@@ -0,0 +1,90 @@
+use std::{collections::VecDeque, num::NonZeroU64, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::StoredMessage;
+
+/// Limits of messages kept for each identifier. Messages over the limits are
+/// removed, starting from the oldest ones.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetentionPolicy {
+    /// Maximum age of message, in seconds.
+    pub max_age: Option<u64>,
+    /// Maximum number of messages kept for identifier.
+    pub max_messages: Option<usize>,
+    /// Maximum total size of messages kept for identifier, in bytes.
+    pub max_bytes: Option<usize>,
+    /// How often expired messages are removed, in seconds.
+    #[serde(default = "RetentionPolicy::default_sweep_interval")]
+    pub sweep_interval: NonZeroU64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            max_messages: None,
+            max_bytes: None,
+            sweep_interval: Self::default_sweep_interval(),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    fn default_sweep_interval() -> NonZeroU64 {
+        NonZeroU64::new(60).unwrap()
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval.get())
+    }
+
+    /// Returns true if limits should be checked whenever message is saved.
+    pub fn limits_size(&self) -> bool {
+        self.max_messages.is_some() || self.max_bytes.is_some()
+    }
+
+    /// Returns true if mailbox with `count` messages of total size `bytes`
+    /// exceeds limits of number or size of messages.
+    pub fn exceeds_size(&self, count: usize, bytes: usize) -> bool {
+        self.max_messages.is_some_and(|max| count > max)
+            || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+
+    /// Returns indexes of messages, that exceed the limits at time `now`.
+    /// `messages` need to be ordered by index.
+    pub fn select_to_drop(
+        &self,
+        messages: &[(usize, StoredMessage)],
+        now: DateTime<Utc>,
+    ) -> Vec<usize> {
+        let (mut to_drop, mut kept): (Vec<_>, VecDeque<_>) = (vec![], VecDeque::new());
+        for (index, msg) in messages {
+            match self.max_age {
+                Some(max_age) if (now - msg.received).num_seconds() >= max_age as i64 => {
+                    to_drop.push(*index)
+                }
+                _ => kept.push_back((*index, msg.size())),
+            }
+        }
+        if let Some(max_messages) = self.max_messages {
+            while kept.len() > max_messages {
+                to_drop.extend(kept.pop_front().map(|(index, _)| index));
+            }
+        };
+        if let Some(max_bytes) = self.max_bytes {
+            let mut total: usize = kept.iter().map(|(_, size)| size).sum();
+            while total > max_bytes {
+                match kept.pop_front() {
+                    Some((index, size)) => {
+                        to_drop.push(index);
+                        total -= size;
+                    }
+                    None => break,
+                }
+            }
+        };
+        to_drop
+    }
+}
@@ -1,9 +1,17 @@
+use chrono::Utc;
 use keri_core::actor::prelude::{HashFunction, HashFunctionCode};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::sync::{mpsc, oneshot};
 
 use crate::{
-    notifier::NotifyHandle, responses_store::ResponsesHandle, storage::StorageHandle,
+    notifier::{
+        default_label, InMemoryRegistrations, NoopBackend, NotificationPreferences, NotifyHandle,
+        NotifyOptions, OutgoingWebhook, WebPushSubscription,
+    },
+    ratelimit::{MessageLimiter, RateLimits},
+    responses_store::{digest, ResponseStatus, ResponsesHandle},
+    storage::{InMemoryStore, RetentionPolicy, StorageHandle, StoredMessage},
     MessageboxError,
 };
 
@@ -22,9 +30,9 @@ pub enum QueryArguments {
     BySn { i: String, s: usize },
 }
 
-impl ToString for MessageType {
-    fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+impl std::fmt::Display for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(&self).unwrap())
     }
 }
 
@@ -37,22 +45,100 @@ pub enum ExchangeArguments {
         i: String,
         a: String,
     },
-    // Save firebase token (f) of given identifier (i)
+    // Save firebase token (f) of device labeled (l) of given identifier (i)
     #[serde(rename = "/auth/f")]
     SetFirebase {
         i: String,
         f: String,
+        #[serde(default = "default_label")]
+        l: String,
+    },
+    // Save Web Push subscription (s) of device labeled (l) of given
+    // identifier (i)
+    #[serde(rename = "/auth/p")]
+    SetWebPush {
+        i: String,
+        s: WebPushSubscription,
+        #[serde(default = "default_label")]
+        l: String,
+    },
+    // Set how given identifier (i) is notified about new messages (n)
+    #[serde(rename = "/auth/n")]
+    SetNotificationPreferences {
+        i: String,
+        n: NotificationPreferences,
+    },
+    // Set outgoing webhook (w), to which events about new messages of given
+    // identifier (i) are POSTed. If (w) is not set, webhook is removed.
+    #[serde(rename = "/auth/w")]
+    SetWebhook {
+        i: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        w: Option<OutgoingWebhook>,
+    },
+    // List latest deliveries to outgoing webhook of given identifier (i)
+    #[serde(rename = "/auth/e")]
+    ListDeliveries {
+        i: String,
+    },
+    // List devices registered by given identifier (i)
+    #[serde(rename = "/auth/l")]
+    ListDevices {
+        i: String,
+    },
+    // Remove device labeled (l) of given identifier (i)
+    #[serde(rename = "/auth/u")]
+    RemoveDevice {
+        i: String,
+        l: String,
+    },
+    // Remove messages of given identifier (i) with digests (d) and all
+    // messages with index lower or equal to (s)
+    Ack {
+        i: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        d: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        s: Option<usize>,
+    },
+    // Allow identifiers (a) to read messages of given identifier (i).
+    // Replaces previously set list.
+    #[serde(rename = "/auth/r")]
+    SetReaders {
+        i: String,
+        a: Vec<String>,
+    },
+    // Accept messages to given identifier (i) only from identifiers (a).
+    // If (a) is not set, messages from all identifiers are accepted.
+    #[serde(rename = "/auth/a")]
+    SetAllowedSenders {
+        i: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        a: Option<Vec<String>>,
+    },
+    // Reject messages to given identifier (i) from identifiers (a).
+    // Replaces previously set list.
+    #[serde(rename = "/auth/b")]
+    SetBlockedSenders {
+        i: String,
+        a: Vec<String>,
     },
 }
 
 pub enum ValidateMessage {
     Authenticate {
+        // Identifier that signed the message
+        signer: String,
         message: String,
+        // Message with signatures attached, in CESR format
+        signed_message: String,
         // where to return result
         sender: oneshot::Sender<Result<Option<String>, MessageboxError>>,
     },
     ProcessAndSave {
+        signer: String,
         message: String,
+        signed_message: String,
     },
 }
 
@@ -62,6 +148,8 @@ pub struct ValidateActor {
     storage: StorageHandle,
     notify: NotifyHandle,
     responses_handle: ResponsesHandle,
+    // Limits of messages, that weren't checked before they were deferred
+    limiter: MessageLimiter,
 }
 
 impl ValidateActor {
@@ -70,38 +158,169 @@ impl ValidateActor {
         storage: StorageHandle,
         notify: NotifyHandle,
         responses: ResponsesHandle,
+        limiter: MessageLimiter,
     ) -> Self {
         ValidateActor {
             receiver,
             storage,
             notify,
             responses_handle: responses,
+            limiter,
+        }
+    }
+
+    /// Checks if `signer` is allowed to read messages of identifier `id`.
+    async fn check_read_access(&self, signer: &str, id: &str) -> Result<(), MessageboxError> {
+        if signer == id
+            || self
+                .storage
+                .settings(id)
+                .await?
+                .readers
+                .iter()
+                .any(|r| r == signer)
+        {
+            Ok(())
+        } else {
+            Err(MessageboxError::AccessDenied(
+                signer.to_string(),
+                id.to_string(),
+            ))
         }
     }
 
-    async fn process(&self, message: &str) -> Result<Option<String>, MessageboxError> {
+    /// Checks if `signer` can register and remove devices of identifier `id`.
+    fn check_device_owner(signer: &str, id: &str) -> Result<(), MessageboxError> {
+        if signer == id {
+            Ok(())
+        } else {
+            Err(MessageboxError::RegistrationDenied(
+                signer.to_string(),
+                id.to_string(),
+            ))
+        }
+    }
+
+    /// Checks if `signer` is the owner of mailbox of identifier `id`.
+    fn check_owner(signer: &str, id: &str) -> Result<(), MessageboxError> {
+        if signer == id {
+            Ok(())
+        } else {
+            Err(MessageboxError::AccessDenied(
+                signer.to_string(),
+                id.to_string(),
+            ))
+        }
+    }
+
+    /// Checks if `sender` is allowed to forward messages to identifier `id`.
+    async fn check_sender(&self, sender: &str, id: &str) -> Result<(), MessageboxError> {
+        if sender == id || self.storage.settings(id).await?.accepts(sender) {
+            Ok(())
+        } else {
+            Err(MessageboxError::SenderRejected(
+                sender.to_string(),
+                id.to_string(),
+            ))
+        }
+    }
+
+    async fn process(
+        &self,
+        signer: &str,
+        message: &str,
+        signed_message: &str,
+    ) -> Result<Option<String>, MessageboxError> {
         if let Ok(parsed) = serde_json::from_str::<MessageType>(message) {
             match parsed {
                 MessageType::Qry(qry) => match qry {
                     QueryArguments::ByDigest { i, d } => {
+                        self.check_read_access(signer, &i).await?;
                         println!("Getting messages by digest {:?}", &d);
-                        Ok(self.storage.get_by_digest(&i, d).await)
+                        self.storage.get_by_digest(&i, d).await
                     }
                     QueryArguments::BySn { i, s } => {
+                        self.check_read_access(signer, &i).await?;
                         println!("Getting messages for {} from index {}", &i, s);
-                        Ok(self.storage.get_by_index(&i, s).await)
+                        self.storage.get_by_index(&i, s).await
                     }
                 },
                 MessageType::Exn(exn) => match exn {
                     ExchangeArguments::Fwd { i, a } => {
+                        self.check_sender(signer, &i).await?;
                         println!("Saving message {} for {}", &a, &i);
                         let digest_algo: HashFunction = (HashFunctionCode::Blake3_256).into();
                         let sai = digest_algo.derive(a.as_bytes()).to_string();
-                        self.storage.save(i.clone(), a, sai).await.to_string();
+                        let message = StoredMessage {
+                            digest: sai,
+                            sender: signer.to_string(),
+                            data: json!(a),
+                            exn: signed_message.to_string(),
+                            received: Utc::now(),
+                        };
+                        self.storage.save(i.clone(), message).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::SetFirebase { i, f: t, l } => {
+                        Self::check_device_owner(signer, &i)?;
+                        self.notify.save_token(i, l, t).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::SetWebPush { i, s, l } => {
+                        Self::check_device_owner(signer, &i)?;
+                        self.notify.save_subscription(i, l, s).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::SetNotificationPreferences { i, n } => {
+                        Self::check_device_owner(signer, &i)?;
+                        self.notify.save_preferences(i, n).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::SetWebhook { i, w } => {
+                        Self::check_device_owner(signer, &i)?;
+                        self.notify.save_webhook(i, w).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::ListDeliveries { i } => {
+                        Self::check_device_owner(signer, &i)?;
+                        let deliveries = self.notify.deliveries(i).await?;
+                        Ok(Some(json!(deliveries).to_string()))
+                    }
+                    ExchangeArguments::ListDevices { i } => {
+                        Self::check_device_owner(signer, &i)?;
+                        let registrations = self.notify.registrations(i).await?;
+                        Ok(Some(json!(registrations).to_string()))
+                    }
+                    ExchangeArguments::RemoveDevice { i, l } => {
+                        Self::check_device_owner(signer, &i)?;
+                        self.notify.remove_registration(i, l).await?;
                         Ok(None)
                     }
-                    ExchangeArguments::SetFirebase { i, f: t } => {
-                        self.notify.save_token(i, t).await;
+                    ExchangeArguments::Ack { i, d, s } => {
+                        Self::check_owner(signer, &i)?;
+                        println!("Removing messages of {}: {:?}, up to {:?}", &i, &d, s);
+                        self.storage.remove(&i, d, s).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::SetReaders { i, a } => {
+                        Self::check_owner(signer, &i)?;
+                        let mut settings = self.storage.settings(&i).await?;
+                        settings.readers = a;
+                        self.storage.save_settings(&i, settings).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::SetAllowedSenders { i, a } => {
+                        Self::check_owner(signer, &i)?;
+                        let mut settings = self.storage.settings(&i).await?;
+                        settings.allowed_senders = a;
+                        self.storage.save_settings(&i, settings).await?;
+                        Ok(None)
+                    }
+                    ExchangeArguments::SetBlockedSenders { i, a } => {
+                        Self::check_owner(signer, &i)?;
+                        let mut settings = self.storage.settings(&i).await?;
+                        settings.blocked_senders = a;
+                        self.storage.save_settings(&i, settings).await?;
                         Ok(None)
                     }
                 },
@@ -113,17 +332,37 @@ impl ValidateActor {
 
     async fn handle_message(&mut self, msg: ValidateMessage) {
         match msg {
-            ValidateMessage::Authenticate { message, sender } => {
-                let _ = sender.send(self.process(&message).await);
+            ValidateMessage::Authenticate {
+                signer,
+                message,
+                signed_message,
+                sender,
+            } => {
+                let _ = sender.send(self.process(&signer, &message, &signed_message).await);
             }
-            ValidateMessage::ProcessAndSave { message } => {
+            ValidateMessage::ProcessAndSave {
+                signer,
+                message,
+                signed_message,
+            } => {
                 println!("\nIn process and save: {}", message);
-                let out = self.process(&message).await.unwrap();
-                if let Some(to_save) = out {
-                    let digest: keri_core::actor::prelude::SelfAddressingIdentifier =
-                        HashFunction::from(HashFunctionCode::Blake3_256).derive(message.as_bytes());
-                    self.responses_handle.save(to_save, digest).await;
+                // Signer is known only now, so its limits are checked here.
+                let result = match self.limiter.check(&signer, &message).await {
+                    Ok(()) => self.process(&signer, &message, &signed_message).await,
+                    Err(e) => Err(e),
                 };
+                let status = match result {
+                    Ok(response) => ResponseStatus::Reverified(response),
+                    Err(e) => {
+                        println!("\nCan't process message {}: {}", message, e);
+                        ResponseStatus::Failed(e.to_string())
+                    }
+                };
+                // Failure is already logged by the responses actor.
+                let _ = self
+                    .responses_handle
+                    .save(digest(&message), signer, status)
+                    .await;
             }
         }
     }
@@ -145,9 +384,10 @@ impl ValidateHandle {
         storage_handle: StorageHandle,
         notify_handle: NotifyHandle,
         responses: ResponsesHandle,
+        limiter: MessageLimiter,
     ) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let actor = ValidateActor::new(receiver, storage_handle, notify_handle, responses);
+        let actor = ValidateActor::new(receiver, storage_handle, notify_handle, responses, limiter);
         tokio::spawn(run_my_actor(actor));
 
         Self {
@@ -155,10 +395,46 @@ impl ValidateHandle {
         }
     }
 
-    pub async fn validate(&self, message: String) -> Result<Option<String>, MessageboxError> {
+    /// Starts validator with storage and responses kept in memory and
+    /// notifier that only logs notifications. Returns it with the storage and
+    /// responses it uses, so messages can be processed without the rest of
+    /// messagebox, as in tests.
+    pub fn in_memory(retention: RetentionPolicy) -> (Self, StorageHandle, ResponsesHandle) {
+        let notify_handle = NotifyHandle::new(
+            Box::new(NoopBackend),
+            None,
+            Box::new(InMemoryRegistrations::default()),
+            NotifyOptions::default(),
+        );
+        let storage_handle = StorageHandle::new(
+            Box::new(InMemoryStore::default()),
+            retention,
+            notify_handle.clone(),
+        );
+        let responses = ResponsesHandle::default();
+        let limiter = MessageLimiter::new(&RateLimits::default(), storage_handle.clone());
+        let validator = Self::new(
+            storage_handle.clone(),
+            notify_handle,
+            responses.clone(),
+            limiter,
+        );
+        (validator, storage_handle, responses)
+    }
+
+    /// Processes message, which signatures were made by `signer`.
+    /// `signed_message` is the message with signatures attached, as received.
+    pub async fn validate(
+        &self,
+        signer: String,
+        message: String,
+        signed_message: String,
+    ) -> Result<Option<String>, MessageboxError> {
         let (send, recv) = oneshot::channel();
         let msg = ValidateMessage::Authenticate {
+            signer,
             message,
+            signed_message,
             sender: send,
         };
 
@@ -175,8 +451,15 @@ impl ValidateHandle {
         }
     }
 
-    pub async fn process_and_save(&self, message: String) {
-        let msg = ValidateMessage::ProcessAndSave { message };
+    /// Processes message of `signer`, that waited for its events, and saves
+    /// response to it. Limits of signer are checked first, because they
+    /// couldn't be checked before message was deferred.
+    pub async fn process_and_save(&self, signer: String, message: String, signed_message: String) {
+        let msg = ValidateMessage::ProcessAndSave {
+            signer,
+            message,
+            signed_message,
+        };
 
         // Ignore send errors. If this send fails, so does the
         // recv.await below. There's no reason to check for the
@@ -184,3 +467,196 @@ impl ValidateHandle {
         let _ = self.validate_sender.send(msg).await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use crate::{
+        ack_up_to_sn, forward_message, list_devices, list_webhook_deliveries,
+        notifier::NotificationPreferences, query_by_sn, register_device_token, remove_device,
+        set_allowed_senders, set_blocked_senders, set_notification_preferences, set_readers,
+        set_webhook, storage::RetentionPolicy, validate::MessageType, MessageboxError,
+    };
+
+    use super::ValidateHandle;
+
+    async fn validate(
+        validator: &ValidateHandle,
+        signer: &str,
+        message: MessageType,
+    ) -> Result<Option<String>, MessageboxError> {
+        // Signatures are already verified here, so they can be omitted.
+        validator
+            .validate(signer.to_string(), message.to_string(), message.to_string())
+            .await
+    }
+
+    #[actix_web::test]
+    async fn test_read_access() -> Result<(), MessageboxError> {
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+
+        let exn = forward_message("Owner".to_string(), "saved0".to_string());
+        validate(&validator, "Sender", exn).await?;
+
+        // Only owner can read its messages.
+        let qry = || query_by_sn("Owner".to_string(), 0);
+        let res: Value =
+            serde_json::from_str(&validate(&validator, "Owner", qry()).await?.unwrap()).unwrap();
+        assert_eq!(res["last_sn"], 0);
+        assert_eq!(res["messages"][0]["sn"], 0);
+        assert_eq!(res["messages"][0]["a"], "saved0");
+        assert_eq!(res["messages"][0]["i"], "Sender");
+        assert_eq!(
+            res["messages"][0]["exn"],
+            forward_message("Owner".to_string(), "saved0".to_string()).to_string()
+        );
+
+        let res = validate(&validator, "Reader", qry()).await;
+        assert!(matches!(res, Err(MessageboxError::AccessDenied(_, _))));
+
+        // Nobody but the owner can grant read access.
+        let grant = || set_readers("Owner".to_string(), vec!["Reader".to_string()]);
+        let res = validate(&validator, "Reader", grant()).await;
+        assert!(matches!(res, Err(MessageboxError::AccessDenied(_, _))));
+
+        validate(&validator, "Owner", grant()).await?;
+        assert!(validate(&validator, "Reader", qry()).await?.is_some());
+
+        // Only owner can remove messages.
+        let ack = || ack_up_to_sn("Owner".to_string(), 0);
+        let res = validate(&validator, "Reader", ack()).await;
+        assert!(matches!(res, Err(MessageboxError::AccessDenied(_, _))));
+        validate(&validator, "Owner", ack()).await?;
+        let res: Value =
+            serde_json::from_str(&validate(&validator, "Owner", qry()).await?.unwrap()).unwrap();
+        assert_eq!(res["last_sn"], 0);
+        assert_eq!(res["messages"], json!([]));
+
+        // Revoke access.
+        validate(
+            &validator,
+            "Owner",
+            set_readers("Owner".to_string(), vec![]),
+        )
+        .await?;
+        let res = validate(&validator, "Reader", qry()).await;
+        assert!(matches!(res, Err(MessageboxError::AccessDenied(_, _))));
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_sender_lists() -> Result<(), MessageboxError> {
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+
+        let fwd = |data: &str| forward_message("Owner".to_string(), data.to_string());
+        let rejected = |res: Result<Option<String>, MessageboxError>| {
+            matches!(res, Err(MessageboxError::SenderRejected(_, _)))
+        };
+
+        // Only owner can set lists.
+        let block = || set_blocked_senders("Owner".to_string(), vec!["Spammer".to_string()]);
+        let res = validate(&validator, "Spammer", block()).await;
+        assert!(matches!(res, Err(MessageboxError::AccessDenied(_, _))));
+        validate(&validator, "Owner", block()).await?;
+
+        assert!(rejected(validate(&validator, "Spammer", fwd("spam")).await));
+        validate(&validator, "Friend", fwd("saved0")).await?;
+        validate(&validator, "Stranger", fwd("saved1")).await?;
+
+        let allow = set_allowed_senders("Owner".to_string(), Some(vec!["Friend".to_string()]));
+        validate(&validator, "Owner", allow).await?;
+        validate(&validator, "Friend", fwd("saved2")).await?;
+        assert!(rejected(
+            validate(&validator, "Stranger", fwd("rejected")).await
+        ));
+        // Owner can always send messages to itself.
+        validate(&validator, "Owner", fwd("saved3")).await?;
+
+        // Remove allow-list, block-list still applies.
+        validate(
+            &validator,
+            "Owner",
+            set_allowed_senders("Owner".to_string(), None),
+        )
+        .await?;
+        validate(&validator, "Stranger", fwd("saved4")).await?;
+        assert!(rejected(validate(&validator, "Spammer", fwd("spam")).await));
+
+        let res: Value = serde_json::from_str(
+            &validate(&validator, "Owner", query_by_sn("Owner".to_string(), 0))
+                .await?
+                .unwrap(),
+        )
+        .unwrap();
+        let saved: Vec<_> = res["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|msg| msg["a"].clone())
+            .collect();
+        assert_eq!(
+            saved,
+            vec!["saved0", "saved1", "saved2", "saved3", "saved4"]
+        );
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_devices() -> Result<(), MessageboxError> {
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+
+        for (label, token) in [("phone", "token0"), ("tablet", "token1")] {
+            let exn = register_device_token("Owner".into(), token.into(), label.into());
+            validate(&validator, "Owner", exn).await?;
+        }
+        let list = || list_devices("Owner".into());
+        let labels = |res: Option<String>| -> Vec<Value> {
+            let res: Value = serde_json::from_str(&res.unwrap()).unwrap();
+            res.as_array()
+                .unwrap()
+                .iter()
+                .map(|device| device["label"].clone())
+                .collect()
+        };
+        assert_eq!(
+            labels(validate(&validator, "Owner", list()).await?),
+            vec!["phone", "tablet"]
+        );
+
+        // Only owner can register, list and remove its devices.
+        let denied = |res: Result<Option<String>, MessageboxError>| {
+            matches!(res, Err(MessageboxError::RegistrationDenied(_, _)))
+        };
+        let exn = register_device_token("Owner".into(), "token2".into(), "phone".into());
+        assert!(denied(validate(&validator, "Other", exn).await));
+        assert!(denied(validate(&validator, "Other", list()).await));
+        let remove = || remove_device("Owner".into(), "phone".into());
+        assert!(denied(validate(&validator, "Other", remove()).await));
+        let preferences =
+            set_notification_preferences("Owner".into(), NotificationPreferences::default());
+        assert!(denied(validate(&validator, "Other", preferences).await));
+        assert!(denied(
+            validate(&validator, "Other", set_webhook("Owner".into(), None)).await
+        ));
+        let deliveries = list_webhook_deliveries("Owner".into());
+        assert!(denied(validate(&validator, "Other", deliveries).await));
+        let deliveries = list_webhook_deliveries("Owner".into());
+        assert_eq!(
+            validate(&validator, "Owner", deliveries).await?,
+            Some("[]".into())
+        );
+        let registrations = validate(&validator, "Owner", list()).await?.unwrap();
+        assert!(registrations.contains("token0") && !registrations.contains("token2"));
+
+        validate(&validator, "Owner", remove()).await?;
+        assert_eq!(
+            labels(validate(&validator, "Owner", list()).await?),
+            vec!["tablet"]
+        );
+
+        Ok(())
+    }
+}
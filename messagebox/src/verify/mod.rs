@@ -5,24 +5,36 @@ mod verifier;
 
 use std::{path::Path, sync::Arc};
 
-use keri_controller::LocationScheme;
-use keri_core::event_message::signature::Signature;
+use keri_controller::{IdentifierPrefix, LocationScheme};
+use keri_core::{actor::prelude::SelfAddressingIdentifier, event_message::signature::Signature};
 use tokio::sync::{
     mpsc::{self},
     oneshot,
 };
 
-use crate::{validate::ValidateHandle, MessageboxError};
+use crate::{responses_store::ResponsesHandle, validate::ValidateHandle, MessageboxError};
 
 use self::verifier::VerifyData;
 
+pub use self::reverify::{InMemoryReverify, PendingMessage, ReverifyStore, SledReverify};
+
 #[derive(Debug)]
 pub enum VerifyMessage {
     Verify {
         message: String,
         signatures: Vec<Signature>,
         // where to return result
-        sender: oneshot::Sender<Result<(), MessageboxError>>,
+        sender: oneshot::Sender<Result<IdentifierPrefix, MessageboxError>>,
+    },
+    Defer {
+        signer: IdentifierPrefix,
+        message: String,
+        signatures: Vec<Signature>,
+        // Message with signatures attached, as received
+        signed_message: String,
+        // where to return result
+        sender:
+            oneshot::Sender<Result<(SelfAddressingIdentifier, Option<String>), MessageboxError>>,
     },
     Oobi {
         message: String,
@@ -40,12 +52,22 @@ pub struct VerifyActor {
 impl VerifyActor {
     async fn setup(
         db_path: &Path,
-        watcher_oobi: LocationScheme,
+        watcher_oobi: Option<LocationScheme>,
         seed: Option<String>,
         receiver: mpsc::Receiver<VerifyMessage>,
         validate_handle: ValidateHandle,
+        responses: ResponsesHandle,
+        reverify: Box<dyn ReverifyStore>,
     ) -> Result<Self, MessageboxError> {
-        let vd = VerifyData::setup(db_path, watcher_oobi, seed, validate_handle).await?;
+        let vd = VerifyData::setup(
+            db_path,
+            watcher_oobi,
+            seed,
+            validate_handle,
+            responses,
+            reverify,
+        )
+        .await?;
         Ok(Self {
             receiver,
             data: Arc::new(vd),
@@ -65,6 +87,11 @@ async fn run_my_actor(actor: VerifyActor) {
         arc_data.clone().handle_task().await;
     });
 
+    let arc_data = actor.data.clone();
+    tokio::spawn(async move {
+        arc_data.resume().await;
+    });
+
     let arc_data = actor.data.clone();
     tokio::spawn(listen(arc_data, actor.receiver));
 }
@@ -79,10 +106,39 @@ impl VerifyHandle {
         db_path: &Path,
         watcher_oobi: LocationScheme,
         validate_handle: ValidateHandle,
+        responses: ResponsesHandle,
+        reverify: Box<dyn ReverifyStore>,
+    ) -> Result<Self, MessageboxError> {
+        Self::start(
+            db_path,
+            Some(watcher_oobi),
+            validate_handle,
+            responses,
+            reverify,
+        )
+        .await
+    }
+
+    /// Starts verifier, that asks `watcher_oobi` for missing events. If
+    /// watcher isn't set, events are never found.
+    pub(crate) async fn start(
+        db_path: &Path,
+        watcher_oobi: Option<LocationScheme>,
+        validate_handle: ValidateHandle,
+        responses: ResponsesHandle,
+        reverify: Box<dyn ReverifyStore>,
     ) -> Result<Self, MessageboxError> {
         let (sender, receiver) = mpsc::channel(8);
-        let actor =
-            VerifyActor::setup(db_path, watcher_oobi, None, receiver, validate_handle).await?;
+        let actor = VerifyActor::setup(
+            db_path,
+            watcher_oobi,
+            None,
+            receiver,
+            validate_handle,
+            responses,
+            reverify,
+        )
+        .await?;
         tokio::spawn(run_my_actor(actor));
 
         Ok(Self {
@@ -108,11 +164,13 @@ impl VerifyHandle {
         }
     }
 
+    /// Verifies signatures of message and returns identifier of its signer.
+    /// Returns `MissingEvent` if events of signer need to be found first.
     pub async fn verify(
         &self,
         message: &str,
         signatures: Vec<Signature>,
-    ) -> Result<(), MessageboxError> {
+    ) -> Result<IdentifierPrefix, MessageboxError> {
         let (send, recv) = oneshot::channel();
         let msg = VerifyMessage::Verify {
             message: message.to_string(),
@@ -132,6 +190,41 @@ impl VerifyHandle {
             }
         }
     }
+
+    /// Queues message, that `signer` is expected to have signed, until its
+    /// events are found, and verifies it again then. `signed_message` is
+    /// the message with signatures attached, as received. Returns digest
+    /// of message, under which its response is saved, and token that lets
+    /// `signer` get it before its events are found. Token is returned only
+    /// when message is queued for the first time.
+    pub async fn defer(
+        &self,
+        signer: IdentifierPrefix,
+        message: String,
+        signatures: Vec<Signature>,
+        signed_message: String,
+    ) -> Result<(SelfAddressingIdentifier, Option<String>), MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = VerifyMessage::Defer {
+            signer,
+            message,
+            signatures,
+            signed_message,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.validate_sender.send(msg).await;
+        match recv.await {
+            Ok(res) => res,
+            Err(_) => {
+                println!("Actor task has been killed");
+                Err(MessageboxError::KilledSender)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -140,15 +233,26 @@ pub mod test {
 
     use keri_controller::{
         config::ControllerConfig, identifier_controller::IdentifierController, BasicPrefix,
-        Controller, KeyManager, LocationScheme, SelfSigningPrefix,
+        Controller, IdentifierPrefix, KeyManager, LocationScheme, SelfSigningPrefix,
+    };
+    use keri_core::{
+        event::sections::seal::EventSeal,
+        event_message::signature::{Signature, SignerData},
+        prefix::IndexedSignature,
     };
     use serde_json::json;
     use tempfile::Builder;
     use tokio::time::sleep;
 
     use crate::{
-        forward_message, notifier::NotifyHandle, responses_store::ResponsesHandle,
-        storage::StorageHandle, validate::ValidateHandle, verify::VerifyHandle, MessageboxError,
+        forward_message,
+        responses_store::{
+            digest, InMemoryResponses, ResponseStatus, ResponsesHandle, ResponsesOptions,
+        },
+        storage::RetentionPolicy,
+        validate::ValidateHandle,
+        verify::{InMemoryReverify, PendingMessage, ReverifyStore, SledReverify, VerifyHandle},
+        MessageboxError,
     };
 
     #[actix_web::test]
@@ -193,7 +297,7 @@ pub mod test {
 
         // Quering mailbox to get receipts
         let query = signing_identifier
-            .query_mailbox(&signing_identifier.id, &[witness_id.clone()])
+            .query_mailbox(&signing_identifier.id, std::slice::from_ref(&witness_id))
             .unwrap();
 
         // Query with wrong signature
@@ -215,32 +319,32 @@ pub mod test {
         let signature = SelfSigningPrefix::Ed25519Sha512(km1.sign(msg.as_bytes()).unwrap());
         let signature = signing_identifier.sign(signature, 0).unwrap();
 
-        let notify_handle =
-            NotifyHandle::new("AAAAky1v068:APA91bHHpGtP6M5h3ICFc9AzY35MrkTmjwblkLlEJ1C0yvkrUu7KDkmkXMzPq2q-0o1l49fKxOeDQaKIkZTTEAIX3Jd45j6KNtSempYqop4Psitvz2Ng7iBz-IeS1SGEs1GpnWseJlpP".to_string());
-        let storage_handle = StorageHandle::new(notify_handle.clone());
-        let response_handle = ResponsesHandle::new();
-        let validator_handle = ValidateHandle::new(
-            storage_handle.clone(),
-            notify_handle,
-            response_handle.clone(),
-        );
+        let (validator_handle, _, response_handle) =
+            ValidateHandle::in_memory(RetentionPolicy::default());
         let watcher_oobi = serde_json::from_str(r#"{"eid":"BF2t2NPc1bwptY1hYV0YCib1JjQ11k9jtuaZemecPF5b","scheme":"http","url":"http://localhost:3236/"}"#).unwrap();
         let root = Builder::new().prefix("test-db2").tempdir().unwrap();
-        let vh = VerifyHandle::new(root.path(), watcher_oobi, validator_handle).await?;
+        let vh = VerifyHandle::new(
+            root.path(),
+            watcher_oobi,
+            validator_handle,
+            response_handle.clone(),
+            Box::new(InMemoryReverify::default()),
+        )
+        .await?;
 
         assert!(matches!(
-            vh.verify(&msg, vec![signature.clone()]).await,
+            vh.verify(msg, vec![signature.clone()]).await,
             Err(MessageboxError::MissingOobi)
         ));
         vh.resolve_oobi(witness_oobi_st.to_string()).await.unwrap();
 
         assert!(matches!(
-            vh.verify(&msg, vec![signature.clone()]).await,
+            vh.verify(msg, vec![signature.clone()]).await,
             Err(MessageboxError::MissingOobi)
         ));
         vh.resolve_oobi(oobi_str.clone()).await.unwrap();
 
-        let r = vh.verify(&msg, vec![signature]).await;
+        let r = vh.verify(msg, vec![signature]).await;
         assert!(r.is_ok());
 
         // Rotate identifier and try to verify again
@@ -262,7 +366,7 @@ pub mod test {
 
         // Querying mailbox to get receipts
         let query = signing_identifier
-            .query_mailbox(&signing_identifier.id, &[witness_id.clone()])
+            .query_mailbox(&signing_identifier.id, std::slice::from_ref(&witness_id))
             .unwrap();
 
         // Query with wrong signature
@@ -283,13 +387,124 @@ pub mod test {
             SelfSigningPrefix::Ed25519Sha512(km1.sign(exn.to_string().as_bytes()).unwrap());
         let signature = signing_identifier.sign(signature, 0).unwrap();
         // vh.resolve_oobi(oobi_str).await.unwrap();
-        let r = vh.verify(&exn.to_string(), vec![signature.clone()]).await;
-        dbg!(&r);
-        assert!(matches!(r, Err(MessageboxError::ResponseNotReady(_))));
+        let (said, _) = match vh.verify(&exn.to_string(), vec![signature.clone()]).await {
+            Err(MessageboxError::MissingEvent(signer, _)) => {
+                vh.defer(
+                    signer,
+                    exn.to_string(),
+                    vec![signature.clone()],
+                    exn.to_string(),
+                )
+                .await?
+            }
+            _ => unreachable!(),
+        };
+        let response = response_handle
+            .get(signing_identifier.id.to_string(), said)
+            .await?
+            .unwrap();
+        assert!(response.status.is_waiting());
         sleep(Duration::from_secs(5)).await;
 
         let r = vh.verify(&exn.to_string(), vec![signature]).await;
         assert!(r.is_ok());
         Ok(())
     }
+
+    #[actix_web::test]
+    async fn test_expired_messages() -> Result<(), MessageboxError> {
+        let options = ResponsesOptions {
+            verification_timeout: Some(0),
+            ..Default::default()
+        };
+        let responses = ResponsesHandle::new(Box::new(InMemoryResponses::default()), options);
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+        let db = sled::Config::new().temporary(true).open()?;
+        let root = Builder::new().prefix("test-expired-db").tempdir().unwrap();
+        let vh = VerifyHandle::start(
+            root.path(),
+            None,
+            validator,
+            responses.clone(),
+            Box::new(SledReverify::new(&db)?),
+        )
+        .await?;
+
+        // Message that waited too long for signer's events is dropped from
+        // the queue and isn't verified anymore.
+        let signer: IdentifierPrefix = "BJq7UABlttINuWJh1Xl2lkqZG4NTdUdqnbFJDa6ZyxCC"
+            .parse()
+            .unwrap();
+        let exn = forward_message(signer.to_string(), "hi there".to_string()).to_string();
+        let said = vh
+            .defer(signer.clone(), exn.clone(), vec![], exn.clone())
+            .await?
+            .0;
+        sleep(Duration::from_millis(100)).await;
+        assert!(SledReverify::new(&db)?.keys()?.is_empty());
+        let response = responses.get(signer.to_string(), said).await?.unwrap();
+        assert_eq!(response.status, ResponseStatus::Expired);
+        Ok(())
+    }
+
+    /// Returns message signed by identifier, whose events aren't known.
+    pub(crate) fn unknown_signer_message() -> (IdentifierPrefix, PendingMessage) {
+        let signer = IdentifierPrefix::SelfAddressing(digest("unknown"));
+        let message = forward_message(signer.to_string(), "hi there".to_string()).to_string();
+        let seal = EventSeal {
+            prefix: signer.clone(),
+            sn: 0,
+            event_digest: digest("icp"),
+        };
+        let signature = Signature::Transferable(
+            SignerData::EventSeal(seal),
+            vec![IndexedSignature::new_both_same(
+                SelfSigningPrefix::Ed25519Sha512(vec![0; 64]),
+                0,
+            )],
+        );
+        let pending = PendingMessage {
+            digest: digest(&message),
+            message: message.clone(),
+            signatures: vec![signature],
+            signed_message: message,
+            attempts: 0,
+        };
+        (signer, pending)
+    }
+
+    #[actix_web::test]
+    async fn test_resume_after_restart() -> Result<(), MessageboxError> {
+        // Message queued before restart waits for events of its signer,
+        // even though signer's witness isn't known anymore.
+        let (signer, pending) = unknown_signer_message();
+        let db = sled::Config::new().temporary(true).open()?;
+        SledReverify::new(&db)?.save_queue(&signer.to_string(), std::slice::from_ref(&pending))?;
+        let responses = ResponsesHandle::default();
+        responses
+            .save(
+                pending.digest.clone(),
+                signer.to_string(),
+                ResponseStatus::Queued,
+            )
+            .await?;
+
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+        let root = Builder::new().prefix("test-resume-db").tempdir().unwrap();
+        VerifyHandle::start(
+            root.path(),
+            None,
+            validator,
+            responses.clone(),
+            Box::new(SledReverify::new(&db)?),
+        )
+        .await?;
+        sleep(Duration::from_millis(100)).await;
+        let response = responses
+            .get(signer.to_string(), pending.digest)
+            .await?
+            .unwrap();
+        assert!(response.status.is_waiting());
+        Ok(())
+    }
 }
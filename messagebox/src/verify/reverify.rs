@@ -1,53 +1,248 @@
 use std::collections::HashMap;
 
 use keri_controller::IdentifierPrefix;
-use keri_core::event_message::signature::Signature;
+use keri_core::{actor::prelude::SelfAddressingIdentifier, event_message::signature::Signature};
+use serde::{Deserialize, Serialize};
+use sled::Tree;
 use tokio::sync::{mpsc, oneshot};
 
-use crate::MessageboxError;
+use crate::{responses_store::digest, MessageboxError};
+
+/// Number of messages of one identifier, that can wait for its events.
+pub const MAX_QUEUED: usize = 100;
+
+/// Message waiting until events of its signer are found.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingMessage {
+    pub digest: SelfAddressingIdentifier,
+    pub message: String,
+    pub signatures: Vec<Signature>,
+    /// Message with signatures attached, as received.
+    pub signed_message: String,
+    /// How many times watcher was asked for signer's events, that weren't
+    /// found.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// Backend in which messages waiting for events of their signers are kept.
+pub trait ReverifyStore: Send {
+    /// Returns messages signed by identifier `key`, in order of arrival.
+    fn queue(&self, key: &str) -> Result<Vec<PendingMessage>, MessageboxError>;
+
+    /// Replaces messages signed by identifier `key`.
+    fn save_queue(&mut self, key: &str, queue: &[PendingMessage]) -> Result<(), MessageboxError>;
+
+    /// Returns identifiers, that signed any waiting message.
+    fn keys(&self) -> Result<Vec<String>, MessageboxError>;
+}
+
+/// Store that keeps waiting messages in memory. They are lost on restart.
+#[derive(Default)]
+pub struct InMemoryReverify {
+    queues: HashMap<String, Vec<PendingMessage>>,
+}
+
+impl ReverifyStore for InMemoryReverify {
+    fn queue(&self, key: &str) -> Result<Vec<PendingMessage>, MessageboxError> {
+        Ok(self.queues.get(key).cloned().unwrap_or_default())
+    }
+
+    fn save_queue(&mut self, key: &str, queue: &[PendingMessage]) -> Result<(), MessageboxError> {
+        if queue.is_empty() {
+            self.queues.remove(key);
+        } else {
+            self.queues.insert(key.to_string(), queue.to_vec());
+        };
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, MessageboxError> {
+        Ok(self.queues.keys().cloned().collect())
+    }
+}
+
+/// Store that keeps waiting messages in sled database, so they survive
+/// restarts.
+pub struct SledReverify {
+    queues: Tree,
+}
+
+impl SledReverify {
+    /// Setup store using tree of already opened database.
+    pub fn new(db: &sled::Db) -> Result<Self, MessageboxError> {
+        Ok(Self {
+            queues: db.open_tree("reverify")?,
+        })
+    }
+}
+
+impl ReverifyStore for SledReverify {
+    fn queue(&self, key: &str) -> Result<Vec<PendingMessage>, MessageboxError> {
+        match self.queues.get(key)? {
+            Some(value) => {
+                serde_json::from_slice(&value).map_err(|e| MessageboxError::Storage(e.to_string()))
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    fn save_queue(&mut self, key: &str, queue: &[PendingMessage]) -> Result<(), MessageboxError> {
+        if queue.is_empty() {
+            self.queues.remove(key)?;
+        } else {
+            let value =
+                serde_json::to_vec(queue).map_err(|e| MessageboxError::Storage(e.to_string()))?;
+            self.queues.insert(key, value)?;
+        };
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, MessageboxError> {
+        self.queues
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8_lossy(&key?).to_string()))
+            .collect()
+    }
+}
 
 #[derive(Debug)]
 pub enum ReverifyMessage {
     Save {
         id: IdentifierPrefix,
-        // digest: SelfAddressingIdentifier,
-        message: String,
-        signatures: Vec<Signature>,
+        message: PendingMessage,
+        // where to return result
+        sender: oneshot::Sender<Result<bool, MessageboxError>>,
+    },
+    Update {
+        id: IdentifierPrefix,
+        message: PendingMessage,
+        sender: oneshot::Sender<Result<bool, MessageboxError>>,
     },
-    Get {
+    Queue {
         id: IdentifierPrefix,
-        sender: oneshot::Sender<(Vec<u8>, Vec<Signature>)>,
+        sender: oneshot::Sender<Result<Vec<PendingMessage>, MessageboxError>>,
+    },
+    Remove {
+        id: IdentifierPrefix,
+        digests: Vec<SelfAddressingIdentifier>,
+        sender: oneshot::Sender<Result<usize, MessageboxError>>,
+    },
+    Identifiers {
+        sender: oneshot::Sender<Result<Vec<IdentifierPrefix>, MessageboxError>>,
     },
 }
 
 pub struct ReverifyActor {
-    reverify_dict: HashMap<IdentifierPrefix, (Vec<u8>, Vec<Signature>)>,
+    // Messages of each signer, in order of arrival
+    store: Box<dyn ReverifyStore>,
     // From where get messages
     receiver: mpsc::Receiver<ReverifyMessage>,
 }
 
 impl ReverifyActor {
-    fn new(receiver: mpsc::Receiver<ReverifyMessage>) -> Self {
-        ReverifyActor {
-            reverify_dict: HashMap::new(),
-            receiver,
+    fn new(receiver: mpsc::Receiver<ReverifyMessage>, store: Box<dyn ReverifyStore>) -> Self {
+        ReverifyActor { store, receiver }
+    }
+
+    /// Adds message to queue of `id`, unless message with the same digest is
+    /// already there. Returns true if queue was empty, or error if it's
+    /// full.
+    fn save(
+        &mut self,
+        id: &IdentifierPrefix,
+        message: PendingMessage,
+    ) -> Result<bool, MessageboxError> {
+        let key = id.to_string();
+        let mut queue = self.store.queue(&key)?;
+        let first = queue.is_empty();
+        if !queue.iter().any(|queued| queued.digest == message.digest) {
+            if queue.len() >= MAX_QUEUED {
+                return Err(MessageboxError::RateLimited(key));
+            };
+            queue.push(message);
+            self.store.save_queue(&key, &queue)?;
+        };
+        Ok(first)
+    }
+
+    /// Replaces queued message of `id` with the same digest, keeping its
+    /// place in queue. Returns false if message isn't queued anymore.
+    fn update(
+        &mut self,
+        id: &IdentifierPrefix,
+        message: PendingMessage,
+    ) -> Result<bool, MessageboxError> {
+        let key = id.to_string();
+        let mut queue = self.store.queue(&key)?;
+        match queue
+            .iter_mut()
+            .find(|queued| queued.digest == message.digest)
+        {
+            Some(queued) => {
+                *queued = message;
+                self.store.save_queue(&key, &queue)?;
+                Ok(true)
+            }
+            None => Ok(false),
         }
     }
+
+    /// Removes messages with `digests` from queue of `id`. Returns number of
+    /// messages left.
+    fn remove(
+        &mut self,
+        id: &IdentifierPrefix,
+        digests: &[SelfAddressingIdentifier],
+    ) -> Result<usize, MessageboxError> {
+        let key = id.to_string();
+        let mut queue = self.store.queue(&key)?;
+        queue.retain(|pending| !digests.contains(&pending.digest));
+        self.store.save_queue(&key, &queue)?;
+        Ok(queue.len())
+    }
+
+    fn identifiers(&self) -> Result<Vec<IdentifierPrefix>, MessageboxError> {
+        self.store
+            .keys()?
+            .iter()
+            .map(|key| {
+                key.parse()
+                    .map_err(|_| MessageboxError::Storage(format!("Invalid identifier: {}", key)))
+            })
+            .collect()
+    }
+
     async fn handle_message(&mut self, msg: ReverifyMessage) {
         match msg {
-            // ReverifyMessage::Save { digest, message } => {
             ReverifyMessage::Save {
                 id,
                 message,
-                signatures,
+                sender,
             } => {
-                println!("\nSaving to verify later: {}", &message);
-                self.reverify_dict
-                    .insert(id, (message.as_bytes().to_vec(), signatures));
+                println!("\nSaving to verify later: {}", &message.message);
+                let _ = sender.send(self.save(&id, message));
             }
-            ReverifyMessage::Get { id, sender } => {
-                let message = self.reverify_dict.get(&id);
-                sender.send(message.unwrap().clone()).unwrap();
+            ReverifyMessage::Update {
+                id,
+                message,
+                sender,
+            } => {
+                let _ = sender.send(self.update(&id, message));
+            }
+            ReverifyMessage::Queue { id, sender } => {
+                let _ = sender.send(self.store.queue(&id.to_string()));
+            }
+            ReverifyMessage::Remove {
+                id,
+                digests,
+                sender,
+            } => {
+                let _ = sender.send(self.remove(&id, &digests));
+            }
+            ReverifyMessage::Identifiers { sender } => {
+                let _ = sender.send(self.identifiers());
             }
         }
     }
@@ -65,9 +260,9 @@ pub struct ReverifyHandle {
 }
 
 impl ReverifyHandle {
-    pub fn new() -> Self {
+    pub fn new(store: Box<dyn ReverifyStore>) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let actor = ReverifyActor::new(receiver);
+        let actor = ReverifyActor::new(receiver, store);
         tokio::spawn(run_my_actor(actor));
 
         Self {
@@ -75,31 +270,67 @@ impl ReverifyHandle {
         }
     }
 
+    /// Queues message signed by `id`, to verify it when its events are
+    /// found. `signed_message` is the message with signatures attached, as
+    /// received. Returns true if it's the only queued message of `id`, so
+    /// watcher needs to be asked for its events.
     pub async fn save(
         &self,
         id: IdentifierPrefix,
         message: String,
         signatures: Vec<Signature>,
-    ) -> Result<(), MessageboxError> {
+        signed_message: String,
+    ) -> Result<bool, MessageboxError> {
+        let pending = PendingMessage {
+            digest: digest(&message),
+            message,
+            signatures,
+            signed_message,
+            attempts: 0,
+        };
+        let (send, recv) = oneshot::channel();
         let msg = ReverifyMessage::Save {
+            id,
+            message: pending,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.validate_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Updates queued message of `id`, that still can't be verified, with
+    /// the same digest. Returns false if message isn't queued anymore.
+    pub async fn update(
+        &self,
+        id: IdentifierPrefix,
+        message: PendingMessage,
+    ) -> Result<bool, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = ReverifyMessage::Update {
             id,
             message,
-            signatures,
+            sender: send,
         };
 
         // Ignore send errors. If this send fails, so does the
         // recv.await below. There's no reason to check for the
         // same failure twice.
         let _ = self.validate_sender.send(msg).await;
-        Ok(())
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
     }
 
-    pub async fn get(
+    /// Returns queued messages of `identifier` in order of arrival, without
+    /// removing them.
+    pub async fn queue(
         &self,
         identifier: IdentifierPrefix,
-    ) -> Result<(Vec<u8>, Vec<Signature>), MessageboxError> {
+    ) -> Result<Vec<PendingMessage>, MessageboxError> {
         let (send, recv) = oneshot::channel();
-        let msg = ReverifyMessage::Get {
+        let msg = ReverifyMessage::Queue {
             id: identifier,
             sender: send,
         };
@@ -108,6 +339,173 @@ impl ReverifyHandle {
         // recv.await below. There's no reason to check for the
         // same failure twice.
         let _ = self.validate_sender.send(msg).await;
-        Ok(recv.await.expect("Err"))
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Removes queued messages of `identifier` with `digests`. Returns
+    /// number of its messages, that are still queued.
+    pub async fn remove(
+        &self,
+        identifier: IdentifierPrefix,
+        digests: Vec<SelfAddressingIdentifier>,
+    ) -> Result<usize, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = ReverifyMessage::Remove {
+            id: identifier,
+            digests,
+            sender: send,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.validate_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+
+    /// Returns identifiers, whose events are needed to verify queued
+    /// messages.
+    pub async fn identifiers(&self) -> Result<Vec<IdentifierPrefix>, MessageboxError> {
+        let (send, recv) = oneshot::channel();
+        let msg = ReverifyMessage::Identifiers { sender: send };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check for the
+        // same failure twice.
+        let _ = self.validate_sender.send(msg).await;
+        recv.await.map_err(|_| MessageboxError::KilledSender)?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use keri_controller::IdentifierPrefix;
+
+    use crate::MessageboxError;
+
+    use super::{InMemoryReverify, ReverifyHandle, ReverifyStore, MAX_QUEUED};
+
+    #[actix_web::test]
+    async fn test_queue() -> Result<(), MessageboxError> {
+        let reverify = ReverifyHandle::new(Box::new(InMemoryReverify::default()));
+        let id: IdentifierPrefix = "BJq7UABlttINuWJh1Xl2lkqZG4NTdUdqnbFJDa6ZyxCC"
+            .parse()
+            .unwrap();
+        let other: IdentifierPrefix = "BF2t2NPc1bwptY1hYV0YCib1JjQ11k9jtuaZemecPF5b"
+            .parse()
+            .unwrap();
+        assert!(reverify.queue(id.clone()).await?.is_empty());
+
+        assert!(
+            reverify
+                .save(id.clone(), "first".into(), vec![], "first".into())
+                .await?
+        );
+        assert!(
+            !reverify
+                .save(id.clone(), "second".into(), vec![], "second".into())
+                .await?
+        );
+        // The same message is queued once.
+        assert!(
+            !reverify
+                .save(id.clone(), "first".into(), vec![], "first".into())
+                .await?
+        );
+        assert!(
+            reverify
+                .save(other.clone(), "other".into(), vec![], "other".into())
+                .await?
+        );
+        let mut identifiers = reverify.identifiers().await?;
+        identifiers.sort_by_key(|id| id.to_string());
+        assert_eq!(identifiers, vec![other.clone(), id.clone()]);
+
+        // Removing messages keeps the rest queued.
+        let queued = reverify.queue(id.clone()).await?;
+        assert_eq!(queued.len(), 2);
+        reverify
+            .save(id.clone(), "third".into(), vec![], "third".into())
+            .await?;
+        let digests = reverify.queue(id.clone()).await?[2..]
+            .iter()
+            .map(|pending| pending.digest.clone())
+            .collect();
+        assert_eq!(reverify.remove(id.clone(), digests).await?, 2);
+        assert_eq!(reverify.queue(id.clone()).await?, queued);
+
+        // Updated message keeps its place in queue.
+        let mut first = queued[0].clone();
+        first.attempts = 2;
+        assert!(reverify.update(id.clone(), first.clone()).await?);
+        let messages = reverify.queue(id.clone()).await?;
+        assert_eq!(messages, vec![first, queued[1].clone()]);
+
+        // Message that isn't queued anymore isn't added again.
+        let digests = messages.iter().map(|pending| pending.digest.clone());
+        assert_eq!(reverify.remove(id.clone(), digests.collect()).await?, 0);
+        assert!(!reverify.update(id.clone(), queued[0].clone()).await?);
+        assert!(reverify.queue(id.clone()).await?.is_empty());
+        let digests = vec![reverify.queue(other.clone()).await?[0].digest.clone()];
+        assert_eq!(reverify.remove(other, digests).await?, 0);
+
+        // Queue of identifier starts again after it was emptied.
+        assert!(
+            reverify
+                .save(id.clone(), "third".into(), vec![], "third".into())
+                .await?
+        );
+        assert_eq!(reverify.identifiers().await?, vec![id]);
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_queue_limit() -> Result<(), MessageboxError> {
+        let reverify = ReverifyHandle::new(Box::new(InMemoryReverify::default()));
+        let id: IdentifierPrefix = "BJq7UABlttINuWJh1Xl2lkqZG4NTdUdqnbFJDa6ZyxCC"
+            .parse()
+            .unwrap();
+        for i in 0..MAX_QUEUED {
+            let message = i.to_string();
+            reverify
+                .save(id.clone(), message.clone(), vec![], message)
+                .await?;
+        }
+        assert!(matches!(
+            reverify
+                .save(id.clone(), "over".into(), vec![], "over".into())
+                .await,
+            Err(MessageboxError::RateLimited(_))
+        ));
+        // Message that is already queued is still accepted.
+        assert!(
+            !reverify
+                .save(id.clone(), "0".into(), vec![], "0".into())
+                .await?
+        );
+        assert_eq!(reverify.queue(id).await?.len(), MAX_QUEUED);
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_saved_queue() -> Result<(), MessageboxError> {
+        // Queue saved before restart is read from store.
+        let mut store = InMemoryReverify::default();
+        let id: IdentifierPrefix = "BJq7UABlttINuWJh1Xl2lkqZG4NTdUdqnbFJDa6ZyxCC"
+            .parse()
+            .unwrap();
+        let saved = {
+            let reverify = ReverifyHandle::new(Box::new(InMemoryReverify::default()));
+            reverify
+                .save(id.clone(), "first".into(), vec![], "first".into())
+                .await?;
+            reverify.queue(id.clone()).await?
+        };
+        store.save_queue(&id.to_string(), &saved)?;
+
+        let reverify = ReverifyHandle::new(Box::new(store));
+        assert_eq!(reverify.identifiers().await?, vec![id.clone()]);
+        assert_eq!(reverify.queue(id).await?, saved);
+        Ok(())
     }
 }
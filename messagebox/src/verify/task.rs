@@ -6,7 +6,11 @@ use crate::MessageboxError;
 
 #[derive(Debug)]
 pub enum VerificationTask {
-    Verify(String, Vec<Signature>, Sender<Result<(), MessageboxError>>),
+    Verify(
+        String,
+        Vec<Signature>,
+        Sender<Result<IdentifierPrefix, MessageboxError>>,
+    ),
     Find(IdentifierPrefix),
     Reverify(IdentifierPrefix),
 }
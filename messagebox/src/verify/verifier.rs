@@ -1,16 +1,11 @@
-use std::{
-    collections::HashMap,
-    path::Path,
-    sync::Arc,
-    time::Duration,
-};
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
 
 use keri_controller::{
     config::ControllerConfig, error::ControllerError, identifier_controller::IdentifierController,
     BasicPrefix, Controller, EndRole, IdentifierPrefix, LocationScheme, Oobi,
 };
-use keri_core::actor::prelude::{HashFunction, HashFunctionCode};
 use keri_core::{
+    actor::prelude::SelfAddressingIdentifier,
     event_message::signature::{Nontransferable, Signature},
     oobi::Role,
     processor::event_storage::EventStorage,
@@ -24,12 +19,30 @@ use tokio::{
     time::sleep,
 };
 
-use crate::{validate::ValidateHandle, MessageboxError};
+use crate::{
+    responses_store::{digest, ResponseStatus, ResponsesHandle},
+    validate::ValidateHandle,
+    MessageboxError,
+};
 
 use super::{
-    reverify::ReverifyHandle, signer::SignerHandle, task::VerificationTask, VerifyMessage,
+    reverify::{PendingMessage, ReverifyHandle, ReverifyStore},
+    signer::SignerHandle,
+    task::VerificationTask,
+    VerifyMessage,
 };
 
+/// How long to wait before asking watcher again, if it doesn't have events
+/// yet.
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+
+/// The longest delay between asking watcher about the same identifier.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How many times watcher is asked for events, before message whose
+/// signature still can't be verified fails.
+const MAX_ATTEMPTS: u32 = 10;
+
 pub(crate) struct VerifyData {
     controller: IdentifierController,
     signer: SignerHandle,
@@ -38,14 +51,19 @@ pub(crate) struct VerifyData {
     task_sender: Sender<VerificationTask>,
     task_queue: Mutex<Receiver<VerificationTask>>,
     validate_handle: ValidateHandle,
+    responses: ResponsesHandle,
+    // Delay before asking watcher again, doubled with each attempt
+    retry_delay: Duration,
 }
 
 impl VerifyData {
     pub async fn setup(
         db_path: &Path,
-        watcher_oobi: LocationScheme,
+        watcher_oobi: Option<LocationScheme>,
         seed: Option<String>,
         validate_handle: ValidateHandle,
+        responses: ResponsesHandle,
+        reverify: Box<dyn ReverifyStore>,
     ) -> Result<Self, MessageboxError> {
         let signer = match seed {
             Some(seed) => SignerHandle::new_with_seed(&seed)?,
@@ -57,34 +75,40 @@ impl VerifyData {
             db_path: db_path.into(),
             ..Default::default()
         })?);
-        let oobi = Oobi::Location(watcher_oobi.clone());
-        controller.resolve_oobi(oobi).await?;
-
         let id = IdentifierController::new(
             IdentifierPrefix::Basic(identifier.clone()),
             controller.clone(),
             None,
         );
-        let end_role = id.add_watcher(watcher_oobi.eid)?;
-        let signature = signer.sign(end_role.clone()).await?;
-        id.finalize_event(end_role.as_bytes(), signature).await?;
+        // Without watcher, only messages of already known signers can be
+        // verified.
+        if let Some(watcher_oobi) = watcher_oobi {
+            let oobi = Oobi::Location(watcher_oobi.clone());
+            controller.resolve_oobi(oobi).await?;
+            let end_role = id.add_watcher(watcher_oobi.eid)?;
+            let signature = signer.sign(end_role.clone()).await?;
+            id.finalize_event(end_role.as_bytes(), signature).await?;
+        };
         let (task_sender, task_receiver) = mpsc::channel(20);
         Ok(VerifyData {
             signer: signer.clone(),
             controller: id,
             witnesses: Arc::new(Mutex::new(HashMap::new())),
-            reverify: ReverifyHandle::new(),
+            reverify: ReverifyHandle::new(reverify),
             task_queue: Mutex::new(task_receiver),
             task_sender,
             validate_handle,
+            responses,
+            retry_delay: RETRY_DELAY,
         })
     }
 
+    /// Checks signature and returns identifier of its signer.
     fn verify(
         s: &Signature,
         data: &[u8],
         storage: Arc<EventStorage>,
-    ) -> Result<bool, MessageboxError> {
+    ) -> Result<IdentifierPrefix, MessageboxError> {
         match s {
             Signature::Transferable(sigd, sigs) => {
                 let (kc, id, event_sai) = match sigd {
@@ -104,15 +128,24 @@ impl VerifyData {
                     ),
                     keri_core::event_message::signature::SignerData::JustSignatures => todo!(),
                 };
-                if let Some(k) = kc {
-                    Ok(k.verify(data, sigs).unwrap())
-                } else {
-                    Err(MessageboxError::MissingEvent(id, event_sai.unwrap()))
+                match kc {
+                    Some(k) if k.verify(data, sigs).unwrap() => Ok(id),
+                    Some(_) => Err(MessageboxError::VerificationFailure),
+                    None => Err(MessageboxError::MissingEvent(id, event_sai.unwrap())),
+                }
+            }
+            Signature::NonTransferable(Nontransferable::Couplet(couplets)) => {
+                match couplets.first() {
+                    Some((signer, _))
+                        if couplets
+                            .iter()
+                            .all(|(id, sig)| id == signer && id.verify(data, sig).unwrap()) =>
+                    {
+                        Ok(IdentifierPrefix::Basic(signer.clone()))
+                    }
+                    _ => Err(MessageboxError::VerificationFailure),
                 }
             }
-            Signature::NonTransferable(Nontransferable::Couplet(couplets)) => Ok(couplets
-                .iter()
-                .all(|(id, sig)| id.verify(data, sig).unwrap())),
             Signature::NonTransferable(Nontransferable::Indexed(_sigs)) => {
                 todo!()
             }
@@ -136,52 +169,198 @@ impl VerifyData {
             .unwrap_or(false)
     }
 
-    async fn ask_watcher(&self, id: &IdentifierPrefix) {
-        // Ask watcher
+    /// Sends query about events of `id` to watcher.
+    async fn query_watcher(&self, id: &IdentifierPrefix) -> Result<(), MessageboxError> {
         let mut query = vec![];
-
-        for qry in self.controller.query_own_watchers(id).unwrap() {
-            let ssp = self
-                .signer
-                .sign(String::from_utf8(qry.encode().unwrap()).unwrap())
-                .await
-                .unwrap();
-            query.push((qry.clone(), ssp));
+        for qry in self.controller.query_own_watchers(id)? {
+            let encoded = String::from_utf8(qry.encode()?)
+                .map_err(|e| MessageboxError::Unparsable(e.to_string()))?;
+            let ssp = self.signer.sign(encoded).await?;
+            query.push((qry, ssp));
         }
-
-        let mut query_result = self.controller.finalize_query(query).await;
+        let query_result = self.controller.finalize_query(query).await;
         println!("\nin ask watcher: {:?}", query_result);
-        while matches!(
-            query_result,
-            Err(ControllerError::TransportError(
-                TransportError::ResponseNotReady
-            ))
-        ) {
-            sleep(Duration::from_secs(3)).await;
-            let mut query = vec![];
-            for qry in self.controller.query_own_watchers(id).unwrap() {
-                let ssp = self
-                    .signer
-                    .sign(String::from_utf8(qry.encode().unwrap()).unwrap())
-                    .await
-                    .unwrap();
-                query.push((qry.clone(), ssp));
+        query_result?;
+        Ok(())
+    }
+
+    /// Asks watcher for events of `id` and reverifies queued messages of
+    /// `id`, when they are found. If watcher doesn't have them yet, it's
+    /// asked again later, until all queued messages expire.
+    async fn ask_watcher(&self, id: &IdentifierPrefix) {
+        match self.remove_expired(id).await {
+            Ok(0) => return,
+            Ok(_) => (),
+            Err(e) => {
+                println!("\nCan't get messages to reverify: {}", e);
+                return;
             }
-            query_result = self.controller.finalize_query(query).await;
-            println!("\nin ask watcher: {:?}", query_result);
-            if query_result.is_ok() {
-                let _ = self.task_sender
+        };
+        self.responses
+            .update_waiting(id.to_string(), ResponseStatus::AskingWatcher)
+            .await;
+        match self.query_watcher(id).await {
+            Ok(()) => {
+                let _ = self
+                    .task_sender
                     .send(VerificationTask::Reverify(id.clone()))
                     .await;
             }
+            Err(MessageboxError::Controller(ControllerError::TransportError(
+                TransportError::ResponseNotReady,
+            ))) => self.find_later(id.clone(), self.retry_delay),
+            Err(e) => {
+                println!("\nCan't get events of {} from watcher: {}", id, e);
+                let mut failed = vec![];
+                for pending in self.reverify.queue(id.clone()).await.unwrap_or_default() {
+                    // Failure is already logged by the responses actor.
+                    let _ = self
+                        .responses
+                        .save(
+                            pending.digest.clone(),
+                            id.to_string(),
+                            ResponseStatus::Failed(e.to_string()),
+                        )
+                        .await;
+                    failed.push(pending.digest);
+                }
+                self.remove_handled(id, failed).await;
+            }
+        }
+    }
+
+    /// Schedules asking watcher about `id` after `delay`, so other tasks
+    /// aren't blocked meanwhile.
+    fn find_later(&self, id: IdentifierPrefix, delay: Duration) {
+        let task_sender = self.task_sender.clone();
+        tokio::spawn(async move {
+            sleep(delay).await;
+            let _ = task_sender.send(VerificationTask::Find(id)).await;
+        });
+    }
+
+    /// Removes messages of `id` with `digests` from queue, after they were
+    /// processed. Messages queued meanwhile didn't make watcher to be
+    /// asked, so it's asked again if any are left.
+    async fn remove_handled(&self, id: &IdentifierPrefix, digests: Vec<SelfAddressingIdentifier>) {
+        match self.reverify.remove(id.clone(), digests).await {
+            Ok(0) => (),
+            Ok(_) => self.find_later(id.clone(), self.retry_delay),
+            Err(e) => println!("\nCan't remove reverified messages: {}", e),
+        }
+    }
+
+    /// Returns false if response to message of `id` with `digest` doesn't
+    /// wait for verification anymore, because it expired.
+    async fn is_waiting(&self, id: &IdentifierPrefix, digest: &SelfAddressingIdentifier) -> bool {
+        // Response can be saved just after the message was queued, so
+        // message without response still waits.
+        !matches!(
+            self.responses.get(id.to_string(), digest.clone()).await,
+            Ok(Some(response)) if !response.status.is_waiting()
+        )
+    }
+
+    /// Removes queued messages of `id`, that don't wait for verification
+    /// anymore. Returns number of messages, that still wait.
+    async fn remove_expired(&self, id: &IdentifierPrefix) -> Result<usize, MessageboxError> {
+        let mut expired = vec![];
+        for pending in self.reverify.queue(id.clone()).await? {
+            if !self.is_waiting(id, &pending.digest).await {
+                expired.push(pending.digest);
+            }
         }
+        self.reverify.remove(id.clone(), expired).await
     }
 
+    /// Verifies again message that was waiting for events of `id`, and
+    /// processes it if signature is valid. If events are still missing,
+    /// message is queued again, until watcher was asked `MAX_ATTEMPTS`
+    /// times. Returns number of attempts, if message was queued again.
+    async fn reverify_message(
+        &self,
+        id: &IdentifierPrefix,
+        pending: PendingMessage,
+    ) -> Option<u32> {
+        let digest = pending.digest.clone();
+        let attempts = pending.attempts + 1;
+        // Witness of signer was known when message was queued, but not
+        // necessarily after restart, so it isn't checked again.
+        let reason = match self.check_signatures(&pending.message, pending.signatures.clone()) {
+            Ok(signer) => {
+                self.validate_handle
+                    .process_and_save(signer.to_string(), pending.message, pending.signed_message)
+                    .await;
+                return None;
+            }
+            Err(MessageboxError::MissingEvent(_, _)) if attempts >= MAX_ATTEMPTS => {
+                format!("Events of {} weren't found", id)
+            }
+            // Still missing events, queue it to be verified again.
+            Err(MessageboxError::MissingEvent(_, _)) => {
+                let pending = PendingMessage {
+                    attempts,
+                    ..pending
+                };
+                match self.reverify.update(id.clone(), pending).await {
+                    Ok(true) => {
+                        let _ = self
+                            .responses
+                            .save(digest, id.to_string(), ResponseStatus::Queued)
+                            .await;
+                        return Some(attempts);
+                    }
+                    // Message was dropped from queue meanwhile.
+                    Ok(false) => return None,
+                    Err(e) => e.to_string(),
+                }
+            }
+            Err(e) => e.to_string(),
+        };
+        let _ = self
+            .responses
+            .save(digest, id.to_string(), ResponseStatus::Failed(reason))
+            .await;
+        None
+    }
+
+    /// Returns how long to wait before asking watcher again, after it was
+    /// asked `attempts` times. Delay doubles with each attempt, up to
+    /// `MAX_RETRY_DELAY`.
+    fn backoff(&self, attempts: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(MAX_RETRY_DELAY)
+    }
+
+    /// Verifies message signatures and returns identifier of its signer.
+    /// All signatures need to be made by the same identifier. If signer's
+    /// events are missing, but its witness is known, returns
+    /// `MissingEvent`, so message can be deferred.
     async fn verify_message(
         &self,
         message: &str,
         signatures: Vec<Signature>,
-    ) -> Result<(), MessageboxError> {
+    ) -> Result<IdentifierPrefix, MessageboxError> {
+        match self.check_signatures(message, signatures) {
+            Err(MessageboxError::MissingEvent(id, said)) => {
+                if self.has_oobi(&id).await {
+                    Err(MessageboxError::MissingEvent(id, said))
+                } else {
+                    Err(MessageboxError::MissingOobi)
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Verifies message signatures with known events. Returns
+    /// `MissingEvent` if signer's events are missing.
+    fn check_signatures(
+        &self,
+        message: &str,
+        signatures: Vec<Signature>,
+    ) -> Result<IdentifierPrefix, MessageboxError> {
         let ver_res = signatures
             .iter()
             .map(|sig| {
@@ -191,63 +370,60 @@ impl VerifyData {
                     self.controller.source.storage.clone(),
                 )
             })
-            .collect::<Result<Vec<bool>, _>>();
+            .collect::<Result<Vec<_>, _>>();
         println!("ver result: {:?}", ver_res);
-        match ver_res {
-            Ok(res) => {
-                if res.into_iter().all(|a| a) {
-                    Ok(())
-                } else {
-                    Err(MessageboxError::VerificationFailure)
-                }
-            }
-            Err(MessageboxError::MissingEvent(id, _said)) => {
-                if self.has_oobi(&id).await {
-                    self.reverify
-                        .save(id.clone(), message.to_string(), signatures)
-                        .await
-                        .unwrap();
-                    // Ask watcher
-                    {
-                        let _ = self.task_sender
-                            .send(VerificationTask::Find(id.clone()))
-                            .await;
-                    }
+        let signers = ver_res?;
+        match signers.first() {
+            Some(signer) if signers.iter().all(|id| id == signer) => Ok(signer.clone()),
+            _ => Err(MessageboxError::VerificationFailure),
+        }
+    }
 
-                    let digest: keri_core::actor::prelude::SelfAddressingIdentifier =
-                        HashFunction::from(HashFunctionCode::Blake3_256).derive(message.as_bytes());
-                    Err(MessageboxError::ResponseNotReady(digest))
-                } else {
-                    Err(MessageboxError::MissingOobi)
-                }
-            }
-            Err(e) => Err(e),
+    /// Queues message to verify it again, when events of `signer` are
+    /// found. Returns digest under which response will be saved, and token
+    /// that lets requester get it, unless message was already queued.
+    async fn defer(
+        &self,
+        signer: IdentifierPrefix,
+        message: String,
+        signatures: Vec<Signature>,
+        signed_message: String,
+    ) -> Result<(SelfAddressingIdentifier, Option<String>), MessageboxError> {
+        let digest = digest(&message);
+        let first = self
+            .reverify
+            .save(signer.clone(), message, signatures, signed_message)
+            .await?;
+        let token = self
+            .responses
+            .defer(digest.clone(), signer.to_string())
+            .await?;
+        // Ask watcher, unless it's already asked about this identifier.
+        if first {
+            let _ = self.task_sender.send(VerificationTask::Find(signer)).await;
         }
+        Ok((digest, token))
     }
 
     async fn handle_oobi(&self, oobi_str: &str) -> Result<(), MessageboxError> {
         let oobi: Oobi =
             serde_json::from_str(oobi_str).map_err(|_| MessageboxError::OobiParsingError)?;
         // Save witness oobi to be able to check, if we know it already!!!!
-        match &oobi {
-            Oobi::EndRole(EndRole {
-                cid,
-                eid: IdentifierPrefix::Basic(bp),
-                role,
-            }) => {
-                if let Role::Witness = role {
-                    let mut w = self.witnesses.lock().await;
-                    match w.get_mut(cid) {
-                        Some(s) => {
-                            s.push(bp.clone());
-                        }
-                        None => {
-                            w.insert(cid.clone(), vec![bp.clone()]);
-                        }
-                    };
-                };
-            }
-            _ => {}
+        if let Oobi::EndRole(EndRole {
+            cid,
+            eid: IdentifierPrefix::Basic(bp),
+            role: Role::Witness,
+        }) = &oobi
+        {
+            let mut w = self.witnesses.lock().await;
+            match w.get_mut(cid) {
+                Some(s) => {
+                    s.push(bp.clone());
+                }
+                None => {
+                    w.insert(cid.clone(), vec![bp.clone()]);
+                }
+            };
         };
         self.controller
             .source
@@ -269,16 +445,43 @@ impl VerifyData {
                 signatures,
                 sender,
             } => {
-                let _ = self.task_sender
+                let _ = self
+                    .task_sender
                     .send(VerificationTask::Verify(message, signatures, sender))
                     .await;
             }
+            VerifyMessage::Defer {
+                signer,
+                message,
+                signatures,
+                signed_message,
+                sender,
+            } => {
+                let _ = sender.send(
+                    self.defer(signer, message, signatures, signed_message)
+                        .await,
+                );
+            }
             VerifyMessage::Oobi { message, sender } => {
                 let _ = sender.send(self.handle_oobi(&message).await);
             }
         }
     }
 
+    /// Asks watcher again about identifiers, whose messages were waiting
+    /// for verification before restart.
+    pub async fn resume(&self) {
+        match self.reverify.identifiers().await {
+            Ok(identifiers) => {
+                for id in identifiers {
+                    println!("\nResume asking watcher about {}", id);
+                    let _ = self.task_sender.send(VerificationTask::Find(id)).await;
+                }
+            }
+            Err(e) => println!("\nCan't get messages to reverify: {}", e),
+        }
+    }
+
     pub async fn handle_task(&self) {
         loop {
             let mut queue = self.task_queue.lock().await;
@@ -294,13 +497,177 @@ impl VerifyData {
                     }
                     VerificationTask::Reverify(id) => {
                         println!("\nHandle reverify task");
-                        let (data, signatures) = self.reverify.get(id.clone()).await.unwrap();
-                        let message = String::from_utf8(data).unwrap();
-                        self.verify_message(&message, signatures).await.unwrap();
-                        self.validate_handle.process_and_save(message).await;
+                        // Messages are kept in queue until they are
+                        // processed, so they aren't lost on restart.
+                        match self.reverify.queue(id.clone()).await {
+                            Ok(messages) => {
+                                let (mut requeued, mut handled) = (None, vec![]);
+                                for pending in messages {
+                                    let digest = pending.digest.clone();
+                                    let attempts = if self.is_waiting(&id, &digest).await {
+                                        self.reverify_message(&id, pending).await
+                                    } else {
+                                        None
+                                    };
+                                    match attempts {
+                                        Some(_) => requeued = requeued.max(attempts),
+                                        None => handled.push(digest),
+                                    }
+                                }
+                                match requeued {
+                                    // Events weren't found yet, ask watcher
+                                    // again later.
+                                    Some(attempts) => {
+                                        if let Err(e) =
+                                            self.reverify.remove(id.clone(), handled).await
+                                        {
+                                            println!("\nCan't remove reverified messages: {}", e);
+                                        };
+                                        self.find_later(id, self.backoff(attempts));
+                                    }
+                                    None => self.remove_handled(&id, handled).await,
+                                }
+                            }
+                            Err(e) => println!("\nCan't get messages to reverify: {}", e),
+                        }
                     }
                 };
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use tempfile::Builder;
+    use tokio::time::sleep;
+
+    use crate::{
+        responses_store::{ResponseStatus, ResponsesHandle},
+        storage::RetentionPolicy,
+        validate::ValidateHandle,
+        verify::{test::unknown_signer_message, InMemoryReverify, PendingMessage},
+        MessageboxError,
+    };
+
+    use super::{VerificationTask, VerifyData};
+
+    #[actix_web::test]
+    async fn test_attempts_limit() -> Result<(), MessageboxError> {
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+        let responses = ResponsesHandle::default();
+        let root = Builder::new().prefix("test-attempts-db").tempdir().unwrap();
+        let mut data = VerifyData::setup(
+            root.path(),
+            None,
+            None,
+            validator,
+            responses.clone(),
+            Box::new(InMemoryReverify::default()),
+        )
+        .await?;
+        data.retry_delay = Duration::from_millis(1);
+        let data = Arc::new(data);
+        let tasks = data.clone();
+        tokio::spawn(async move { tasks.handle_task().await });
+
+        // Without watcher, signer's events are never found. Message is
+        // verified again with growing delays, until it fails.
+        let (signer, pending) = unknown_signer_message();
+        let (said, _) = data
+            .defer(
+                signer.clone(),
+                pending.message,
+                pending.signatures,
+                pending.signed_message,
+            )
+            .await?;
+        let mut status = ResponseStatus::Queued;
+        for _ in 0..100 {
+            sleep(Duration::from_millis(50)).await;
+            let response = responses.get(signer.to_string(), said.clone()).await?;
+            status = response.unwrap().status;
+            if !status.is_waiting() {
+                break;
+            }
+        }
+        assert_eq!(
+            status,
+            ResponseStatus::Failed(format!("Events of {} weren't found", signer))
+        );
+        assert!(data.reverify.identifiers().await?.is_empty());
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_queue_kept() -> Result<(), MessageboxError> {
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+        let responses = ResponsesHandle::default();
+        let root = Builder::new().prefix("test-queue-db").tempdir().unwrap();
+        let data = Arc::new(
+            VerifyData::setup(
+                root.path(),
+                None,
+                None,
+                validator,
+                responses.clone(),
+                Box::new(InMemoryReverify::default()),
+            )
+            .await?,
+        );
+        let tasks = data.clone();
+        tokio::spawn(async move { tasks.handle_task().await });
+
+        // Message stays in queue while it's verified again, and its
+        // attempts are updated in place.
+        let (signer, pending) = unknown_signer_message();
+        responses
+            .defer(pending.digest.clone(), signer.to_string())
+            .await?;
+        data.reverify
+            .save(
+                signer.clone(),
+                pending.message.clone(),
+                pending.signatures.clone(),
+                pending.signed_message.clone(),
+            )
+            .await?;
+        let _ = data
+            .task_sender
+            .send(VerificationTask::Reverify(signer.clone()))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let queued = data.reverify.queue(signer.clone()).await?;
+        assert_eq!(
+            queued,
+            vec![PendingMessage {
+                attempts: 1,
+                ..pending
+            }]
+        );
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_backoff() -> Result<(), MessageboxError> {
+        let (validator, _, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+        let root = Builder::new().prefix("test-backoff-db").tempdir().unwrap();
+        let data = VerifyData::setup(
+            root.path(),
+            None,
+            None,
+            validator,
+            ResponsesHandle::default(),
+            Box::new(InMemoryReverify::default()),
+        )
+        .await?;
+        let delays: Vec<_> = [1, 2, 3, 5, 6, 40]
+            .into_iter()
+            .map(|attempts| data.backoff(attempts).as_secs())
+            .collect();
+        assert_eq!(delays, vec![3, 6, 12, 48, 60, 60]);
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, Closed, Session};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    messagebox::{has_messages, MessageBox},
+    random_token, MessageboxError,
+};
+
+/// How long client has to answer the challenge.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answer to challenge, signed by identifier opening the session.
+#[derive(Deserialize)]
+struct ChallengeResponse {
+    /// Challenge sent by messagebox.
+    c: String,
+    /// Index of the first message of identifier to push.
+    #[serde(default)]
+    s: usize,
+}
+
+/// Messages sent by messagebox to client.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "t", rename_all = "lowercase")]
+enum ServerMessage {
+    /// Random value client needs to sign to open the session.
+    Challenge { c: String },
+    /// Session is open for identifier (i).
+    Authenticated { i: String },
+    /// New messages of identifier, as returned by `qry` message.
+    Messages {
+        #[serde(flatten)]
+        m: Value,
+    },
+    /// Response to message sent by client.
+    Response { r: Option<Value> },
+    /// Message (d) waits for events of its signer. Its response is returned
+    /// by `/messages/<d>` endpoint, with token (k) until events are found.
+    Deferred {
+        d: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        k: Option<String>,
+    },
+    /// Message sent by client couldn't be processed.
+    Error { e: String },
+}
+
+/// Upgrades request to WebSocket session. Client needs to sign the
+/// challenge first, then it gets new messages of its mailbox and can send
+/// signed messages, like to `POST /`.
+pub async fn connect(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<Arc<MessageBox>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, stream) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(run_session(
+        data.get_ref().clone(),
+        req.peer_addr().map(|addr| addr.ip()),
+        session,
+        stream.aggregate_continuations(),
+    ));
+    Ok(response)
+}
+
+async fn run_session(
+    messagebox: Arc<MessageBox>,
+    peer: Option<IpAddr>,
+    mut session: Session,
+    mut stream: AggregatedMessageStream,
+) {
+    let (identifier, mut next_sn) = match authenticate(&messagebox, &mut session, &mut stream).await
+    {
+        Ok(Some(authenticated)) => authenticated,
+        Ok(None) => return,
+        Err(e) => {
+            println!("WebSocket authentication failed: {}", e);
+            let _ = send(&mut session, ServerMessage::Error { e: e.to_string() }).await;
+            let _ = session.close(Some(CloseCode::Policy.into())).await;
+            return;
+        }
+    };
+    println!("Opened WebSocket session of {}", identifier);
+
+    // Subscribe before getting messages, not to miss message saved in
+    // between.
+    let mut saved = messagebox.storage_handle.subscribe();
+    let authenticated = ServerMessage::Authenticated {
+        i: identifier.clone(),
+    };
+    if send(&mut session, authenticated).await.is_err()
+        || push_messages(&messagebox, &mut session, &identifier, &mut next_sn)
+            .await
+            .is_err()
+    {
+        return;
+    };
+
+    loop {
+        let result = tokio::select! {
+            msg = stream.recv() => match msg {
+                Some(Ok(AggregatedMessage::Text(text))) => {
+                    let reply = match messagebox.process_message(text.to_string(), peer).await {
+                        Ok(response) => ServerMessage::Response {
+                            r: response.map(|response| {
+                                serde_json::from_str(&response).unwrap_or(Value::String(response))
+                            }),
+                        },
+                        Err(MessageboxError::Deferred(said, token)) => ServerMessage::Deferred {
+                            d: said.to_string(),
+                            k: token,
+                        },
+                        Err(e) => ServerMessage::Error { e: e.to_string() },
+                    };
+                    send(&mut session, reply).await
+                }
+                Some(Ok(AggregatedMessage::Ping(bytes))) => session.pong(&bytes).await,
+                Some(Ok(AggregatedMessage::Close(reason))) => {
+                    let _ = session.close(reason).await;
+                    break;
+                }
+                Some(Ok(_)) => Ok(()),
+                Some(Err(_)) | None => break,
+            },
+            key = saved.recv() => match key {
+                Ok(key) if key != identifier => Ok(()),
+                // Some identifiers were skipped, so check to be sure.
+                Ok(_) | Err(RecvError::Lagged(_)) => {
+                    push_messages(&messagebox, &mut session, &identifier, &mut next_sn).await
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if result.is_err() {
+            break;
+        };
+    }
+    println!("Closed WebSocket session of {}", identifier);
+}
+
+/// Sends challenge and waits for client to sign it. Returns identifier that
+/// signed it and index of the first message to push, or `None` if client
+/// disconnected.
+async fn authenticate(
+    messagebox: &MessageBox,
+    session: &mut Session,
+    stream: &mut AggregatedMessageStream,
+) -> Result<Option<(String, usize)>, MessageboxError> {
+    let challenge = random_token()?;
+    let msg = ServerMessage::Challenge {
+        c: challenge.clone(),
+    };
+    if send(session, msg).await.is_err() {
+        return Ok(None);
+    };
+    let body = loop {
+        match tokio::time::timeout(AUTH_TIMEOUT, stream.recv()).await {
+            Ok(Some(Ok(AggregatedMessage::Text(text)))) => break text.to_string(),
+            Ok(Some(Ok(AggregatedMessage::Ping(bytes)))) => {
+                if session.pong(&bytes).await.is_err() {
+                    return Ok(None);
+                }
+            }
+            Ok(Some(Ok(AggregatedMessage::Close(_)))) | Ok(Some(Err(_))) | Ok(None) => {
+                return Ok(None)
+            }
+            Ok(Some(Ok(_))) => (),
+            Err(_) => return Err(MessageboxError::VerificationFailure),
+        }
+    };
+    // Challenge is answered only once, so it's not queued until events of
+    // signer are found.
+    let (signer, payload) = messagebox.verify_now(&body).await?;
+    check_challenge(&payload, &challenge).map(|sn| Some((signer, sn)))
+}
+
+/// Checks if `payload` answers `challenge`. Returns index of the first
+/// message to push.
+fn check_challenge(payload: &str, challenge: &str) -> Result<usize, MessageboxError> {
+    let response: ChallengeResponse = serde_json::from_str(payload)
+        .map_err(|_| MessageboxError::UnknownMessage(payload.to_string()))?;
+    if response.c == challenge {
+        Ok(response.s)
+    } else {
+        Err(MessageboxError::VerificationFailure)
+    }
+}
+
+/// Sends messages of `identifier` from index `next_sn`, if there are any,
+/// and moves `next_sn` past them.
+async fn push_messages(
+    messagebox: &MessageBox,
+    session: &mut Session,
+    identifier: &str,
+    next_sn: &mut usize,
+) -> Result<(), Closed> {
+    let messages = match messagebox
+        .storage_handle
+        .get_by_index(identifier, *next_sn)
+        .await
+    {
+        Ok(messages) if has_messages(&messages) => messages.unwrap_or_default(),
+        Ok(_) => return Ok(()),
+        Err(e) => return send(session, ServerMessage::Error { e: e.to_string() }).await,
+    };
+    let messages: Value = serde_json::from_str(&messages).unwrap_or_default();
+    if let Some(last_sn) = messages["last_sn"].as_u64() {
+        *next_sn = last_sn as usize + 1;
+    };
+    send(session, ServerMessage::Messages { m: messages }).await
+}
+
+async fn send(session: &mut Session, msg: ServerMessage) -> Result<(), Closed> {
+    session
+        .text(serde_json::to_string(&msg).unwrap_or_default())
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use actix_codec::{Decoder, Encoder};
+    use actix_http::ws::{Codec, Frame, Message};
+    use actix_web::{web, App, HttpServer};
+    use bytes::BytesMut;
+    use keri_core::signer::Signer;
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use crate::{
+        forward_message,
+        messagebox::test::{identifier, setup, sign},
+        MessageboxError,
+    };
+
+    use super::{check_challenge, connect, random_token, ServerMessage};
+
+    #[test]
+    fn test_challenge() -> Result<(), MessageboxError> {
+        let challenge = random_token()?;
+        assert_ne!(challenge, random_token()?);
+
+        let payload = json!({"c": challenge, "s": 3}).to_string();
+        assert_eq!(check_challenge(&payload, &challenge)?, 3);
+        let payload = json!({ "c": challenge }).to_string();
+        assert_eq!(check_challenge(&payload, &challenge)?, 0);
+        let payload = json!({"c": "other", "s": 3}).to_string();
+        assert!(matches!(
+            check_challenge(&payload, &challenge),
+            Err(MessageboxError::VerificationFailure)
+        ));
+
+        let messages = ServerMessage::Messages {
+            m: json!({"last_sn": 1, "messages": []}),
+        };
+        assert_eq!(
+            serde_json::to_value(&messages).unwrap(),
+            json!({"t": "messages", "last_sn": 1, "messages": []})
+        );
+        Ok(())
+    }
+
+    /// WebSocket client, that sends and receives JSON text frames.
+    struct Client {
+        stream: TcpStream,
+        codec: Codec,
+        buffer: BytesMut,
+    }
+
+    impl Client {
+        async fn connect(address: SocketAddr) -> Self {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let request = format!(
+                "GET /ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                address
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut buffer = BytesMut::new();
+            let headers_end = loop {
+                assert_ne!(stream.read_buf(&mut buffer).await.unwrap(), 0);
+                if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                };
+            };
+            let headers = buffer.split_to(headers_end);
+            assert!(headers.starts_with(b"HTTP/1.1 101"));
+            Self {
+                stream,
+                codec: Codec::new().client_mode(),
+                buffer,
+            }
+        }
+
+        async fn send(&mut self, text: String) {
+            let mut frame = BytesMut::new();
+            self.codec
+                .encode(Message::Text(text.into()), &mut frame)
+                .unwrap();
+            self.stream.write_all(&frame).await.unwrap();
+        }
+
+        /// Returns next text message, or `None` if session was closed.
+        async fn recv(&mut self) -> Option<Value> {
+            loop {
+                match self.codec.decode(&mut self.buffer).unwrap() {
+                    Some(Frame::Text(text)) => return serde_json::from_slice(&text).ok(),
+                    Some(Frame::Close(_)) => return None,
+                    Some(_) => continue,
+                    None => (),
+                };
+                let read = tokio::time::timeout(
+                    Duration::from_secs(5),
+                    self.stream.read_buf(&mut self.buffer),
+                )
+                .await
+                .expect("no message from messagebox")
+                .unwrap();
+                if read == 0 {
+                    return None;
+                };
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_session() -> Result<(), anyhow::Error> {
+        let (messagebox, _kel_root, _oobi_root) = setup().await;
+        let messagebox = Arc::new(messagebox);
+        let data = web::Data::new(messagebox.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/ws", web::get().to(connect))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))?;
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let alice = Signer::new();
+        let bob = Signer::new();
+
+        // Session is closed, if challenge isn't answered correctly.
+        let mut client = Client::connect(address).await;
+        assert_eq!(client.recv().await.unwrap()["t"], "challenge");
+        client
+            .send(sign(&alice, &json!({"c": "other"}).to_string()))
+            .await;
+        assert_eq!(client.recv().await.unwrap()["t"], "error");
+        assert_eq!(client.recv().await, None);
+
+        let mut client = Client::connect(address).await;
+        let challenge = client.recv().await.unwrap();
+        assert_eq!(challenge["t"], "challenge");
+        let answer = json!({"c": challenge["c"], "s": 0}).to_string();
+        client.send(sign(&alice, &answer)).await;
+        assert_eq!(
+            client.recv().await.unwrap(),
+            json!({"t": "authenticated", "i": identifier(&alice)})
+        );
+
+        // Message forwarded to alice is pushed to her session.
+        let fwd = forward_message(identifier(&alice), "hi alice".to_string()).to_string();
+        assert_eq!(
+            messagebox.process_message(sign(&bob, &fwd), None).await?,
+            None
+        );
+        let pushed = client.recv().await.unwrap();
+        assert_eq!(pushed["t"], "messages");
+        assert_eq!(pushed["last_sn"], 0);
+        assert_eq!(pushed["messages"][0]["a"], "hi alice");
+
+        // Signed message sent in session is processed.
+        let fwd = forward_message(identifier(&bob), "hi bob".to_string()).to_string();
+        client.send(sign(&alice, &fwd)).await;
+        assert_eq!(
+            client.recv().await.unwrap(),
+            json!({"t": "response", "r": null})
+        );
+        let saved = messagebox
+            .storage_handle
+            .get_by_index(&identifier(&bob), 0)
+            .await?
+            .unwrap();
+        let saved: Value = serde_json::from_str(&saved)?;
+        assert_eq!(saved["messages"][0]["a"], "hi bob");
+        assert_eq!(saved["messages"][0]["i"], identifier(&alice));
+        Ok(())
+    }
+}
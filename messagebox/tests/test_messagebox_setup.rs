@@ -43,7 +43,7 @@ async fn test_messagebox_location() -> Result<(), Error> {
         .unwrap();
     let message_box_oobi: LocationScheme = serde_json::from_str(&format!(
         r#"{{"eid":"{}","scheme":"http","url":"http://messagebox.sandbox.argo.colossi.network"}}"#,
-        message_box_id.to_string()
+        message_box_id
     ))
     .unwrap();
 
@@ -103,8 +103,7 @@ async fn test_messagebox_location() -> Result<(), Error> {
 
     let end_role_oobi = format!(
         r#"{{"cid":"{}","role":"messagebox","eid":"{}"}}"#,
-        &identifier1.id,
-        &message_box_id.to_string()
+        &identifier1.id, &message_box_id
     );
     // Resolve oobis that specify messagebox of identifier1
     identifier2
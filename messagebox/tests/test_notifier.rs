@@ -0,0 +1,525 @@
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use messagebox::{
+    notifier::{
+        FcmClient, FcmConfig, InMemoryRegistrations, NoopBackend, NotificationBackend,
+        NotificationPreferences, NotifyHandle, NotifyOptions, OutgoingWebhook, RetryPolicy,
+        WebPushClient, WebPushConfig, WebPushKeys, WebPushSubscription, WebhookBackend,
+        WebhookConfig, WebhookDelivery, DEFAULT_TITLE, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    },
+    MessageboxError,
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256},
+    hkdf::{KeyType, Prk, Salt, HKDF_SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+    signature::{self, ECDSA_P256_SHA256_FIXED},
+};
+use serde_json::{json, Value};
+use tempfile::Builder;
+
+/// Requests received by stub: path, authorization header and body.
+type Requests = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+/// Stands in for both OAuth2 token endpoint and FCM API. Fails for some
+/// device tokens the way FCM does.
+async fn stub(req: HttpRequest, body: web::Bytes, requests: web::Data<Requests>) -> HttpResponse {
+    let auth = req
+        .headers()
+        .get("Authorization")
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    requests
+        .lock()
+        .unwrap()
+        .push((req.path().to_string(), auth, body.to_vec()));
+    if req.path() == "/token" {
+        HttpResponse::Ok().json(json!({
+            "access_token": "access-token",
+            "expires_in": 3600,
+            "token_type": "Bearer"
+        }))
+    } else {
+        let body: Value = serde_json::from_slice(&body).unwrap_or_default();
+        match body["message"]["token"].as_str() {
+            Some("stale-token") => HttpResponse::NotFound().json(json!({
+                "error": {
+                    "code": 404,
+                    "status": "NOT_FOUND",
+                    "details": [{"errorCode": "UNREGISTERED"}]
+                }
+            })),
+            Some("malformed-token") => HttpResponse::BadRequest().json(json!({
+                "error": {"code": 400, "status": "INVALID_ARGUMENT"}
+            })),
+            Some("busy-token") => HttpResponse::ServiceUnavailable().finish(),
+            _ => HttpResponse::Ok().json(json!({"name": "projects/test-project/messages/1"})),
+        }
+    }
+}
+
+#[actix_web::test]
+async fn test_fcm_v1() -> Result<(), anyhow::Error> {
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let data = web::Data::new(requests.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .default_service(web::to(stub))
+    })
+    .bind(("127.0.0.1", 0))?;
+    let url = format!("http://{}/", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    // Point service account to stub token endpoint.
+    let mut account: Value = serde_json::from_str(include_str!("fixtures/service_account.json"))?;
+    account["token_uri"] = json!(format!("{}token", url));
+    let root = Builder::new().prefix("test-fcm").tempdir()?;
+    let account_path = root.path().join("service_account.json");
+    std::fs::write(&account_path, account.to_string())?;
+
+    let client = Arc::new(FcmClient::load(&FcmConfig {
+        service_account: account_path,
+        base_url: url.parse()?,
+    })?);
+    // Default notification, and silent one without identifier.
+    let silent = NotificationPreferences {
+        silent: true,
+        identifier: false,
+        ..Default::default()
+    };
+    for preferences in [NotificationPreferences::default(), silent] {
+        let client = client.clone();
+        let notification = preferences.notification("Identifier", "digest0", 0, 1);
+        tokio::task::spawn_blocking(move || client.send("device-token", &notification)).await??;
+    }
+
+    {
+        let requests = requests.lock().unwrap();
+        // Access token is minted once and reused.
+        assert_eq!(requests.len(), 3);
+        let (path, _, form) = &requests[0];
+        let form = String::from_utf8(form.clone())?;
+        assert_eq!(path, "/token");
+        assert!(form.contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer"));
+        let jwt = form.split("assertion=").nth(1).unwrap();
+        let parts: Vec<_> = jwt.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1])?)?;
+        assert_eq!(
+            claims["iss"],
+            "messagebox@test-project.iam.gserviceaccount.com"
+        );
+        assert_eq!(claims["aud"], format!("{}token", url));
+
+        for (path, auth, _) in &requests[1..] {
+            assert_eq!(path, "/v1/projects/test-project/messages:send");
+            assert_eq!(auth, "Bearer access-token");
+        }
+        let shown: Value = serde_json::from_slice(&requests[1].2)?;
+        assert_eq!(shown["message"]["token"], "device-token");
+        assert_eq!(shown["message"]["data"]["d"], "digest0");
+        assert_eq!(shown["message"]["data"]["i"], "Identifier");
+        assert_eq!(shown["message"]["data"]["n"], "1");
+        assert_eq!(shown["message"]["notification"]["title"], DEFAULT_TITLE);
+        assert_eq!(
+            shown["message"]["notification"]["body"],
+            "Open the app to read it."
+        );
+        let silent: Value = serde_json::from_slice(&requests[2].2)?;
+        assert_eq!(silent["message"]["data"]["d"], "digest0");
+        assert!(silent["message"]["data"].get("i").is_none());
+        assert!(silent["message"].get("notification").is_none());
+        assert!(!silent.to_string().contains("Identifier"));
+    }
+
+    // Errors are classified, so notifier knows whether to retry or to
+    // remove token.
+    let send = |token: &'static str| {
+        let client = client.clone();
+        let notification =
+            NotificationPreferences::default().notification("Identifier", "digest2", 2, 1);
+        tokio::task::spawn_blocking(move || client.send(token, &notification))
+    };
+    assert!(matches!(
+        send("stale-token").await?,
+        Err(MessageboxError::InvalidNotificationTarget(_))
+    ));
+    assert!(matches!(
+        send("malformed-token").await?,
+        Err(MessageboxError::NotificationRejected(_))
+    ));
+    assert!(matches!(
+        send("busy-token").await?,
+        Err(MessageboxError::Notification(_))
+    ));
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_webhook() -> Result<(), anyhow::Error> {
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let data = web::Data::new(requests.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .default_service(web::to(stub))
+    })
+    .bind(("127.0.0.1", 0))?;
+    let url = format!("http://{}/push", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    let backend = WebhookBackend::new(&WebhookConfig { url: url.parse()? });
+    let notification =
+        NotificationPreferences::default().notification("Identifier", "digest0", 0, 1);
+    tokio::task::spawn_blocking(move || backend.send("device-token", &notification)).await??;
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let (path, _, body) = &requests[0];
+    assert_eq!(path, "/push");
+    assert_eq!(
+        serde_json::from_slice::<Value>(body)?,
+        json!({
+            "token": "device-token",
+            "title": DEFAULT_TITLE,
+            "i": "Identifier",
+            "d": "digest0",
+            "s": 0,
+            "n": 1
+        })
+    );
+
+    Ok(())
+}
+
+/// Receives events of outgoing webhooks. Requests to `/fail` always fail.
+/// Timestamp and signature headers are kept separated by space.
+async fn event_stub(
+    req: HttpRequest,
+    body: web::Bytes,
+    requests: web::Data<Requests>,
+) -> HttpResponse {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default()
+    };
+    let signature = format!("{} {}", header(TIMESTAMP_HEADER), header(SIGNATURE_HEADER));
+    requests
+        .lock()
+        .unwrap()
+        .push((req.path().to_string(), signature, body.to_vec()));
+    if req.path() == "/fail" {
+        HttpResponse::InternalServerError().finish()
+    } else {
+        HttpResponse::NoContent().finish()
+    }
+}
+
+/// Waits until `count` deliveries are logged for `identifier`.
+async fn wait_for_deliveries(
+    handle: &NotifyHandle,
+    identifier: &str,
+    count: usize,
+) -> Result<Vec<WebhookDelivery>, MessageboxError> {
+    for _ in 0..100 {
+        let deliveries = handle.deliveries(identifier.to_string()).await?;
+        if deliveries.len() >= count {
+            return Ok(deliveries);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    handle.deliveries(identifier.to_string()).await
+}
+
+#[actix_web::test]
+async fn test_outgoing_webhook() -> Result<(), anyhow::Error> {
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let data = web::Data::new(requests.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .default_service(web::to(event_stub))
+    })
+    .bind(("127.0.0.1", 0))?;
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+
+    let handle = NotifyHandle::new(
+        Box::new(NoopBackend),
+        None,
+        Box::new(InMemoryRegistrations::default()),
+        NotifyOptions {
+            notification_retry: RetryPolicy {
+                max_attempts: 2,
+                initial_backoff: 10,
+                max_backoff: 10,
+            },
+            webhook_allowed_hosts: vec!["127.0.0.1".into()],
+            ..Default::default()
+        },
+    );
+    // Only https URLs are accepted, unless host is allowed.
+    let webhook = OutgoingWebhook {
+        url: "http://example.com/events".parse()?,
+        secret: "shared secret".into(),
+    };
+    assert!(matches!(
+        handle
+            .save_webhook("Identifier".into(), Some(webhook))
+            .await,
+        Err(MessageboxError::WebhookRejected(_))
+    ));
+
+    let webhook = OutgoingWebhook {
+        url: format!("http://{}/events", address).parse()?,
+        secret: "shared secret".into(),
+    };
+    handle
+        .save_webhook("Identifier".into(), Some(webhook.clone()))
+        .await?;
+    handle
+        .notify("Identifier".into(), "digest0".into(), 0)
+        .await;
+    // Identifier without webhook gets no events.
+    handle.notify("Other".into(), "digest1".into(), 0).await;
+
+    let deliveries = wait_for_deliveries(&handle, "Identifier", 1).await?;
+    assert_eq!(deliveries.len(), 1);
+    assert!(deliveries[0].delivered);
+    assert_eq!(deliveries[0].digest, "digest0");
+    assert_eq!(deliveries[0].error, None);
+    assert!(handle.deliveries("Other".into()).await?.is_empty());
+
+    {
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (path, headers, body) = &requests[0];
+        assert_eq!(path, "/events");
+        let (timestamp, signature) = headers.split_once(' ').unwrap();
+        let timestamp: i64 = timestamp.parse()?;
+        assert!((chrono::Utc::now().timestamp() - timestamp).abs() < 60);
+        // Receiver checks signature of timestamp and body with shared secret.
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"shared secret");
+        let tag = signature.strip_prefix("sha256=").unwrap();
+        let tag: Vec<u8> = (0..tag.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&tag[i..i + 2], 16).unwrap())
+            .collect();
+        let signed = [format!("{}.", timestamp).as_bytes(), body].concat();
+        hmac::verify(&key, &signed, &tag).unwrap();
+        assert_eq!(signature, webhook.signature(timestamp, body));
+
+        let event: Value = serde_json::from_slice(body)?;
+        assert_eq!(event["t"], "message");
+        assert_eq!(event["i"], "Identifier");
+        assert_eq!(event["d"], "digest0");
+        assert_eq!(event["s"], 0);
+    }
+
+    // Failing deliveries are retried, then logged as failed.
+    let webhook = OutgoingWebhook {
+        url: format!("http://{}/fail", address).parse()?,
+        secret: "shared secret".into(),
+    };
+    handle
+        .save_webhook("Identifier".into(), Some(webhook))
+        .await?;
+    handle
+        .notify("Identifier".into(), "digest2".into(), 1)
+        .await;
+    let deliveries = wait_for_deliveries(&handle, "Identifier", 2).await?;
+    assert_eq!(deliveries.len(), 2);
+    assert!(!deliveries[1].delivered);
+    assert_eq!(deliveries[1].sn, 1);
+    assert!(deliveries[1].error.is_some());
+    assert_eq!(requests.lock().unwrap().len(), 3);
+
+    let stats = handle.webhook_stats();
+    assert_eq!(stats.delivered, 1);
+    assert_eq!(stats.retried, 1);
+    assert_eq!(stats.failed, 1);
+    // Events are not counted as notifications.
+    assert_eq!(handle.stats(), Default::default());
+
+    // Removed webhook gets no more events.
+    handle.save_webhook("Identifier".into(), None).await?;
+    handle
+        .notify("Identifier".into(), "digest3".into(), 2)
+        .await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(requests.lock().unwrap().len(), 3);
+
+    Ok(())
+}
+
+struct Len(usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn expand(prk: Prk, info: &[u8], len: usize) -> Vec<u8> {
+    let mut out = vec![0; len];
+    prk.expand(&[info], Len(len))
+        .unwrap()
+        .fill(&mut out)
+        .unwrap();
+    out
+}
+
+#[actix_web::test]
+async fn test_web_push() -> Result<(), anyhow::Error> {
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let data = web::Data::new(requests.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .default_service(web::to(stub))
+    })
+    .bind(("127.0.0.1", 0))?;
+    let origin = format!("http://{}", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    // Keys of user agent.
+    let rng = SystemRandom::new();
+    let ua_private = EphemeralPrivateKey::generate(&ECDH_P256, &rng).unwrap();
+    let ua_public = ua_private.compute_public_key().unwrap();
+    let mut auth_secret = [0u8; 16];
+    rng.fill(&mut auth_secret).unwrap();
+    let subscription = WebPushSubscription {
+        endpoint: format!("{}/push/subscription-id", origin).parse()?,
+        keys: WebPushKeys {
+            p256dh: URL_SAFE_NO_PAD.encode(ua_public.as_ref()),
+            auth: URL_SAFE_NO_PAD.encode(auth_secret),
+        },
+    };
+
+    let config = WebPushConfig {
+        vapid_key: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/vapid_key.pem").into(),
+        subject: "mailto:admin@example.com".to_string(),
+        allowed_hosts: vec![],
+    };
+    let notification =
+        NotificationPreferences::default().notification("Identifier", "digest0", 0, 1);
+    // Push service in private network is reached only if its host is
+    // allowed.
+    let client = WebPushClient::load(&config)?;
+    let (rejected, rejected_notification) = (subscription.clone(), notification.clone());
+    assert!(matches!(
+        tokio::task::spawn_blocking(move || client.send(&rejected, &rejected_notification)).await?,
+        Err(MessageboxError::EndpointRejected(_))
+    ));
+
+    let client = WebPushClient::load(&WebPushConfig {
+        allowed_hosts: vec!["127.0.0.1".into()],
+        ..config
+    })?;
+    let vapid_key = client.public_key();
+    tokio::task::spawn_blocking(move || client.send(&subscription, &notification)).await??;
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let (path, auth, body) = &requests[0];
+    assert_eq!(path, "/push/subscription-id");
+
+    // Check VAPID token.
+    let (token, key) = auth
+        .strip_prefix("vapid t=")
+        .and_then(|auth| auth.split_once(", k="))
+        .unwrap();
+    assert_eq!(key, vapid_key);
+    let (to_sign, signature) = token.rsplit_once('.').unwrap();
+    signature::UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, URL_SAFE_NO_PAD.decode(key)?)
+        .verify(to_sign.as_bytes(), &URL_SAFE_NO_PAD.decode(signature)?)
+        .unwrap();
+    let claims: Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(to_sign.split('.').nth(1).unwrap())?)?;
+    assert_eq!(claims["aud"], origin);
+    assert_eq!(claims["sub"], "mailto:admin@example.com");
+
+    // Decrypt payload as user agent.
+    let (salt, rest) = body.split_at(16);
+    let key_id_len = rest[4] as usize;
+    let (as_public, ciphertext) = rest[5..].split_at(key_id_len);
+    let ecdh_secret = agreement::agree_ephemeral(
+        ua_private,
+        &UnparsedPublicKey::new(&ECDH_P256, as_public),
+        |secret| secret.to_vec(),
+    )
+    .unwrap();
+    let key_info = [b"WebPush: info\0".as_slice(), ua_public.as_ref(), as_public].concat();
+    let ikm = expand(
+        Salt::new(HKDF_SHA256, &auth_secret).extract(&ecdh_secret),
+        &key_info,
+        32,
+    );
+    let prk = Salt::new(HKDF_SHA256, salt).extract(&ikm);
+    let cek = expand(prk.clone(), b"Content-Encoding: aes128gcm\0", 16);
+    let nonce = expand(prk, b"Content-Encoding: nonce\0", 12);
+    let mut record = ciphertext.to_vec();
+    let plaintext = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &cek).unwrap())
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(&nonce).unwrap(),
+            Aad::empty(),
+            &mut record,
+        )
+        .unwrap();
+    assert_eq!(plaintext.last(), Some(&2));
+    assert_eq!(
+        serde_json::from_slice::<Value>(&plaintext[..plaintext.len() - 1])?,
+        json!({"title": DEFAULT_TITLE, "d": "digest0", "i": "Identifier", "s": 0, "n": 1})
+    );
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_web_push_endpoints() -> Result<(), anyhow::Error> {
+    let handle = NotifyHandle::new(
+        Box::new(NoopBackend),
+        None,
+        Box::new(InMemoryRegistrations::default()),
+        NotifyOptions::default(),
+    );
+    let subscription = |endpoint: &str| WebPushSubscription {
+        endpoint: endpoint.parse().unwrap(),
+        keys: WebPushKeys {
+            p256dh: "p256dh".into(),
+            auth: "auth".into(),
+        },
+    };
+
+    // Only https endpoints with public addresses are registered.
+    for endpoint in [
+        "http://93.184.215.14/push/id",
+        "https://127.0.0.1/push/id",
+        "https://169.254.169.254/latest/meta-data",
+        "https://[fd00::1]/push/id",
+    ] {
+        assert!(matches!(
+            handle
+                .save_subscription("Identifier".into(), "phone".into(), subscription(endpoint))
+                .await,
+            Err(MessageboxError::EndpointRejected(_))
+        ));
+    }
+    handle
+        .save_subscription(
+            "Identifier".into(),
+            "phone".into(),
+            subscription("https://93.184.215.14/push/id"),
+        )
+        .await?;
+    assert_eq!(handle.registrations("Identifier".into()).await?.len(), 1);
+    Ok(())
+}
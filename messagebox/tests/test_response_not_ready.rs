@@ -6,7 +6,13 @@ pub mod test {
         config::ControllerConfig, identifier_controller::IdentifierController, BasicPrefix,
         Controller, CryptoBox, KeyManager, LocationScheme, SelfSigningPrefix,
     };
-    use messagebox::{forward_message, messagebox::MessageBox, query_by_sn, MessageboxError};
+    use messagebox::{
+        forward_message,
+        messagebox::{MessageBox, MessageBoxOptions},
+        query_by_sn,
+        storage::Stores,
+        MessageboxError,
+    };
     use serde_json::json;
     use tempfile::Builder;
     use tokio::time::sleep;
@@ -48,7 +54,7 @@ pub mod test {
 
         // Quering mailbox to get receipts
         let query = signing_identifier
-            .query_mailbox(&signing_identifier.id, &[witness_id.clone()])
+            .query_mailbox(&signing_identifier.id, std::slice::from_ref(&witness_id))
             .unwrap();
 
         // Query with wrong signature
@@ -79,7 +85,9 @@ pub mod test {
 
         // Publishing rotation after messagebox resolve oobi, to let him retrieve it from watcher.
         // Quering mailbox to get receipts
-        let query = id.query_mailbox(&id.id, &[witness_id.clone()]).unwrap();
+        let query = id
+            .query_mailbox(&id.id, std::slice::from_ref(&witness_id))
+            .unwrap();
 
         // Query with wrong signature
         {
@@ -115,7 +123,17 @@ pub mod test {
         let watcher_oobi = serde_json::from_str(r#"{"eid":"BF2t2NPc1bwptY1hYV0YCib1JjQ11k9jtuaZemecPF5b","scheme":"http","url":"http://localhost:3236/"}"#).unwrap();
 
         // Setup messagebox
-        let msg_box = MessageBox::setup(messagebox_db.path(), messagebox_oobi_db.path(), watcher_oobi, "http://url.com".parse().unwrap(), None, Some("AAAAky1v068:APA91bHHpGtP6M5h3ICFc9AzY35MrkTmjwblkLlEJ1C0yvkrUu7KDkmkXMzPq2q-0o1l49fKxOeDQaKIkZTTEAIX3Jd45j6KNtSempYqop4Psitvz2Ng7iBz-IeS1SGEs1GpnWseJlpP".to_string())).await.unwrap();
+        let msg_box = MessageBox::setup(
+            messagebox_db.path(),
+            messagebox_oobi_db.path(),
+            watcher_oobi,
+            "http://url.com".parse().unwrap(),
+            None,
+            Stores::in_memory(),
+            MessageBoxOptions::default(),
+        )
+        .await
+        .unwrap();
 
         msg_box
             .resolve_oobi(witness_oobi_st.to_string())
@@ -143,7 +161,7 @@ pub mod test {
             .sign_to_cesr(&exn_msg.to_string(), signature, 0)
             .unwrap();
 
-        let r = msg_box.process_message(signed_exn).await;
+        let r = msg_box.process_message(signed_exn, None).await;
         assert!(r.is_ok());
 
         let qry_msg = query_by_sn("Identifier".to_string(), 0);
@@ -153,12 +171,31 @@ pub mod test {
             .sign_to_cesr(&qry_msg.to_string(), signature, 0)
             .unwrap();
 
-        let r = msg_box.process_message(signed_query).await;
+        let r = msg_box.process_message(signed_query, None).await;
         dbg!(&r);
-        if let Err(MessageboxError::ResponseNotReady(sai)) = r {
+        if let Err(MessageboxError::Deferred(sai, _)) = r {
             sleep(Duration::from_secs(5)).await;
-            let response = msg_box.get_responses(sai).await;
-            assert!(response.is_some());
+            // Response is released only to identifier that sent the query.
+            let request = json!({ "d": sai.to_string() }).to_string();
+            let signature = SelfSigningPrefix::Ed25519Sha512(km1.sign(request.as_bytes()).unwrap());
+            let signed_request = querying_identifier
+                .sign_to_cesr(&request, signature, 0)
+                .unwrap();
+            let response = msg_box
+                .get_response(sai.clone(), &signed_request, None)
+                .await;
+            assert!(!matches!(
+                response,
+                Err(MessageboxError::ResponseNotReady(_) | MessageboxError::UnknownResponse(_))
+            ));
+
+            let signature = SelfSigningPrefix::Ed25519Sha512(km2.sign(request.as_bytes()).unwrap());
+            let signed_request = inserting_identifier
+                .sign_to_cesr(&request, signature, 0)
+                .unwrap();
+            // Other identifier doesn't know about it.
+            let response = msg_box.get_response(sai, &signed_request, None).await;
+            assert!(matches!(response, Err(MessageboxError::UnknownResponse(_))));
         } else {
             unreachable!()
         };
@@ -0,0 +1,496 @@
+use std::{io::ErrorKind, path::Path};
+
+use chrono::{DateTime, Duration, Utc};
+use keri_core::{
+    actor::prelude::{HashFunction, HashFunctionCode},
+    event_message::signature::{Nontransferable, Signature},
+};
+use messagebox::{
+    notifier::{
+        InMemoryRegistrations, NotificationPreferences, OutgoingWebhook, Registration,
+        RegistrationStore, SledRegistrations, Target, WebPushKeys, WebPushSubscription,
+        WebhookDelivery,
+    },
+    responses_store::{
+        InMemoryResponses, ResponseStatus, ResponseStore, SledResponses, StoredResponse,
+    },
+    storage::{InMemoryStore, MailboxStore, Message, RetentionPolicy, SledStore, StoredMessage},
+    validate::ValidateHandle,
+    verify::{InMemoryReverify, PendingMessage, ReverifyStore, SledReverify},
+    MessageboxError,
+};
+use serde_json::json;
+use tempfile::Builder;
+
+/// Opens database without background flush thread, so it can be reopened
+/// right after being dropped. Lock of the dropped database can still be held
+/// for a moment, so opening is retried.
+fn open_db(path: &Path) -> sled::Db {
+    let config = sled::Config::new().path(path).flush_every_ms(None);
+    for _ in 0..300 {
+        match config.open() {
+            Ok(db) => return db,
+            // Lock error keeps `WouldBlock` only in its message.
+            Err(sled::Error::Io(e))
+                if e.kind() == ErrorKind::WouldBlock
+                    || e.to_string().contains("could not acquire lock") =>
+            {
+                std::thread::sleep(std::time::Duration::from_millis(10))
+            }
+            Err(e) => panic!("Can't open database: {}", e),
+        }
+    }
+    config.open().unwrap()
+}
+
+/// Runs `check` on in-memory store and on sled store. Sled store is then
+/// reopened and `reopened` checks that the data were kept.
+fn check_backends<S: ?Sized>(
+    in_memory: Box<S>,
+    sled: fn(&sled::Db) -> Box<S>,
+    check: fn(&mut S),
+    reopened: fn(&mut S),
+) {
+    let mut store = in_memory;
+    check(&mut *store);
+
+    let root = Builder::new().prefix("test-store-db").tempdir().unwrap();
+    {
+        let db = open_db(root.path());
+        check(&mut *sled(&db));
+        db.flush().unwrap();
+    }
+    reopened(&mut *sled(&open_db(root.path())));
+}
+
+fn message(digest: &str, data: &str) -> StoredMessage {
+    StoredMessage {
+        digest: digest.to_string(),
+        sender: "Sender".to_string(),
+        data: json!(data),
+        exn: format!(r#"{{"t":"exn","r":"fwd","i":"Identifier","a":"{}"}}"#, data),
+        received: Utc::now(),
+    }
+}
+
+/// Returns indexes and forwarded data of messages.
+fn data(messages: Vec<(usize, StoredMessage)>) -> Vec<(usize, Message)> {
+    messages
+        .into_iter()
+        .map(|(index, msg)| (index, msg.data))
+        .collect()
+}
+
+fn get_by_sn(store: &dyn MailboxStore, sn: usize) -> Option<(usize, Vec<(usize, Message)>)> {
+    store
+        .get_by_sn("Identifier", sn)
+        .unwrap()
+        .map(|(last_sn, messages)| (last_sn, data(messages)))
+}
+
+fn check_store(store: &mut (dyn MailboxStore + 'static)) {
+    for (i, (digest, msg)) in [("d0", "saved0"), ("d1", "saved1"), ("d2", "saved2")]
+        .into_iter()
+        .enumerate()
+    {
+        assert_eq!(store.save("Identifier", message(digest, msg)).unwrap(), i);
+    }
+    assert_eq!(
+        store.save("Identifier2", message("d2", "other")).unwrap(),
+        0
+    );
+
+    assert_eq!(
+        get_by_sn(store, 1),
+        Some((2, vec![(1, json!("saved1")), (2, json!("saved2"))]))
+    );
+    assert_eq!(get_by_sn(store, 3), Some((2, vec![])));
+    assert_eq!(
+        data(store.oldest("Identifier", 2).unwrap()),
+        vec![(0, json!("saved0")), (1, json!("saved1"))]
+    );
+    assert!(store.oldest("Unknown", 2).unwrap().is_empty());
+    assert_eq!(get_by_sn(store, 4), None);
+    assert_eq!(store.get_by_sn("Unknown", 0).unwrap(), None);
+
+    assert_eq!(
+        store
+            .get_by_digest("Identifier", &["d0".to_string(), "d2".to_string()])
+            .unwrap()
+            .map(data),
+        Some(vec![(0, json!("saved0")), (2, json!("saved2"))])
+    );
+    assert_eq!(
+        store.get_by_digest("Unknown", &["d0".to_string()]).unwrap(),
+        None
+    );
+
+    // Removing messages doesn't change indexes of remaining ones.
+    store.remove("Identifier", &[1]).unwrap();
+    assert_eq!(
+        get_by_sn(store, 0),
+        Some((2, vec![(0, json!("saved0")), (2, json!("saved2"))]))
+    );
+    store.remove("Identifier", &[0, 2]).unwrap();
+    assert_eq!(get_by_sn(store, 0), Some((2, vec![])));
+    assert_eq!(
+        store.save("Identifier", message("d3", "saved3")).unwrap(),
+        3
+    );
+    assert_eq!(get_by_sn(store, 0), Some((3, vec![(3, json!("saved3"))])));
+
+    assert_eq!(store.dropped("Identifier").unwrap(), 0);
+    store.add_dropped("Identifier", 2).unwrap();
+    store.add_dropped("Identifier", 1).unwrap();
+    assert_eq!(store.dropped("Identifier").unwrap(), 3);
+    let mut keys = store.keys().unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["Identifier", "Identifier2"]);
+}
+
+#[test]
+fn test_mailbox_stores() {
+    check_backends::<dyn MailboxStore>(
+        Box::new(InMemoryStore::default()),
+        |db| Box::new(SledStore::new(db).unwrap()),
+        check_store,
+        |store| {
+            // Messages and its indexes are kept after reopening.
+            assert_eq!(
+                store.save("Identifier", message("d4", "saved4")).unwrap(),
+                4
+            );
+            assert_eq!(
+                get_by_sn(store, 3),
+                Some((4, vec![(3, json!("saved3")), (4, json!("saved4"))]))
+            );
+            assert_eq!(store.dropped("Identifier").unwrap(), 3);
+        },
+    );
+}
+
+#[test]
+fn test_retention_policy() {
+    let now = Utc::now();
+    let messages: Vec<_> = (0..4)
+        .map(|i| {
+            let mut msg = message(&format!("d{}", i), "data");
+            msg.received = now - Duration::seconds(40 - 10 * i);
+            (i as usize, msg)
+        })
+        .collect();
+    let size = messages[0].1.size();
+
+    let policy = RetentionPolicy::default();
+    assert!(policy.select_to_drop(&messages, now).is_empty());
+
+    // Messages received 30 and 40 seconds ago are expired.
+    let policy = RetentionPolicy {
+        max_age: Some(30),
+        ..Default::default()
+    };
+    assert_eq!(policy.select_to_drop(&messages, now), vec![0, 1]);
+
+    // Only the newest messages are kept.
+    let policy = RetentionPolicy {
+        max_messages: Some(1),
+        ..Default::default()
+    };
+    assert!(policy.limits_size());
+    assert_eq!(policy.select_to_drop(&messages, now), vec![0, 1, 2]);
+
+    let policy = RetentionPolicy {
+        max_bytes: Some(2 * size + 1),
+        ..Default::default()
+    };
+    assert_eq!(policy.select_to_drop(&messages, now), vec![0, 1]);
+
+    // Limits are combined.
+    let policy = RetentionPolicy {
+        max_age: Some(35),
+        max_messages: Some(2),
+        max_bytes: Some(size),
+        ..Default::default()
+    };
+    assert_eq!(policy.select_to_drop(&messages, now), vec![0, 1, 2]);
+
+    // Single message that exceeds size limit is dropped too.
+    let policy = RetentionPolicy {
+        max_bytes: Some(size - 1),
+        ..Default::default()
+    };
+    assert_eq!(policy.select_to_drop(&messages, now), vec![0, 1, 2, 3]);
+
+    // Sweep can't run continuously.
+    assert!(serde_json::from_value::<RetentionPolicy>(json!({"sweep_interval": 0})).is_err());
+    let policy: RetentionPolicy = serde_json::from_value(json!({"max_messages": 1})).unwrap();
+    assert_eq!(policy.sweep_interval(), std::time::Duration::from_secs(60));
+}
+
+#[actix_web::test]
+async fn test_size_limits() {
+    let size = message("d0", "saved0").size();
+    let (_, storage, _) = ValidateHandle::in_memory(RetentionPolicy {
+        max_messages: Some(3),
+        max_bytes: Some(2 * size + 1),
+        ..Default::default()
+    });
+    let mut saved = storage.subscribe();
+    for i in 0..4 {
+        let msg = message(&format!("d{}", i), &format!("saved{}", i));
+        storage.save("Identifier".into(), msg).await.unwrap();
+        assert_eq!(saved.recv().await.unwrap(), "Identifier");
+    }
+    let response: serde_json::Value = serde_json::from_str(
+        &storage
+            .get_by_index("Identifier", 0)
+            .await
+            .unwrap()
+            .unwrap(),
+    )
+    .unwrap();
+    let kept: Vec<_> = response["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|msg| msg["a"].clone())
+        .collect();
+    assert_eq!(kept, vec!["saved2", "saved3"]);
+    assert_eq!(response["dropped"], 2);
+
+    // Message over the size limit isn't saved at all.
+    let res = storage
+        .save("Identifier".into(), message("d4", &"x".repeat(2 * size)))
+        .await;
+    assert!(matches!(res, Err(MessageboxError::MessageTooLarge(_, _))));
+    assert!(saved.try_recv().is_err());
+    let response = storage
+        .get_by_index("Identifier", 4)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(response.contains(r#""messages":[]"#));
+}
+
+/// Fixed creation time, so registrations can be compared after reopening.
+fn created() -> DateTime<Utc> {
+    "2024-01-01T00:00:00Z".parse().unwrap()
+}
+
+fn registrations() -> Vec<Registration> {
+    vec![
+        Registration {
+            label: "phone".to_string(),
+            target: Target::Token {
+                token: "token".to_string(),
+            },
+            created: created(),
+        },
+        Registration {
+            label: "browser".to_string(),
+            target: Target::WebPush {
+                subscription: WebPushSubscription {
+                    endpoint: "https://push.example.com/id".parse().unwrap(),
+                    keys: WebPushKeys {
+                        p256dh: "p256dh".to_string(),
+                        auth: "auth".to_string(),
+                    },
+                },
+            },
+            created: created(),
+        },
+    ]
+}
+
+fn preferences() -> NotificationPreferences {
+    NotificationPreferences {
+        silent: true,
+        identifier: false,
+        ..Default::default()
+    }
+}
+
+fn check_registrations(store: &mut (dyn RegistrationStore + 'static)) {
+    let registrations = registrations();
+    assert!(store.registrations("Identifier").unwrap().is_empty());
+    store
+        .save_registrations("Identifier", &registrations)
+        .unwrap();
+    store
+        .save_registrations("Identifier2", &registrations[..1])
+        .unwrap();
+    assert_eq!(store.registrations("Identifier").unwrap(), registrations);
+
+    store.save_registrations("Identifier2", &[]).unwrap();
+    assert!(store.registrations("Identifier2").unwrap().is_empty());
+
+    let preferences = preferences();
+    assert_eq!(
+        store.preferences("Identifier").unwrap(),
+        NotificationPreferences::default()
+    );
+    store.save_preferences("Identifier", &preferences).unwrap();
+    assert_eq!(store.preferences("Identifier").unwrap(), preferences);
+
+    let webhook = OutgoingWebhook {
+        url: "https://example.com/events".parse().unwrap(),
+        secret: "secret".into(),
+    };
+    assert_eq!(store.webhook("Identifier").unwrap(), None);
+    store.save_webhook("Identifier", Some(&webhook)).unwrap();
+    assert_eq!(store.webhook("Identifier").unwrap(), Some(webhook));
+    store.save_webhook("Identifier2", None).unwrap();
+    assert_eq!(store.webhook("Identifier2").unwrap(), None);
+
+    let deliveries = vec![
+        WebhookDelivery {
+            digest: "digest0".into(),
+            sn: 0,
+            time: Utc::now(),
+            delivered: true,
+            error: None,
+        },
+        WebhookDelivery {
+            digest: "digest1".into(),
+            sn: 1,
+            time: Utc::now(),
+            delivered: false,
+            error: Some("500: ".into()),
+        },
+    ];
+    assert!(store.deliveries("Identifier").unwrap().is_empty());
+    store.save_deliveries("Identifier", &deliveries).unwrap();
+    assert_eq!(store.deliveries("Identifier").unwrap(), deliveries);
+}
+
+#[test]
+fn test_registration_stores() {
+    check_backends::<dyn RegistrationStore>(
+        Box::new(InMemoryRegistrations::default()),
+        |db| Box::new(SledRegistrations::new(db).unwrap()),
+        check_registrations,
+        |store| {
+            // Registrations and preferences are kept after reopening.
+            assert_eq!(store.registrations("Identifier").unwrap(), registrations());
+            assert_eq!(store.preferences("Identifier").unwrap(), preferences());
+        },
+    );
+}
+
+fn response() -> StoredResponse {
+    StoredResponse {
+        requester: "Requester".into(),
+        status: ResponseStatus::Reverified(Some("response".into())),
+        received: created(),
+        updated: created(),
+        token: None,
+    }
+}
+
+fn check_responses(store: &mut (dyn ResponseStore + 'static)) {
+    let response = response();
+    assert_eq!(store.get("digest0").unwrap(), None);
+    store.save("digest0", &response).unwrap();
+    store
+        .save(
+            "digest1",
+            &StoredResponse {
+                status: ResponseStatus::Failed("Verification failed".into()),
+                ..response.clone()
+            },
+        )
+        .unwrap();
+    assert_eq!(store.get("digest0").unwrap(), Some(response));
+    assert_eq!(store.responses().unwrap().len(), 2);
+    assert_eq!(store.responses_by_prefix("digest").unwrap().len(), 2);
+    let digests: Vec<_> = store
+        .responses_by_prefix("digest1")
+        .unwrap()
+        .into_iter()
+        .map(|(digest, _)| digest)
+        .collect();
+    assert_eq!(digests, vec!["digest1"]);
+
+    store.remove("digest1").unwrap();
+    assert_eq!(store.get("digest1").unwrap(), None);
+    let digests: Vec<_> = store
+        .responses()
+        .unwrap()
+        .into_iter()
+        .map(|(digest, _)| digest)
+        .collect();
+    assert_eq!(digests, vec!["digest0"]);
+}
+
+#[test]
+fn test_response_stores() {
+    check_backends::<dyn ResponseStore>(
+        Box::new(InMemoryResponses::default()),
+        |db| Box::new(SledResponses::new(db).unwrap()),
+        check_responses,
+        |store| {
+            // Responses are kept after reopening.
+            assert_eq!(store.get("digest0").unwrap(), Some(response()));
+        },
+    );
+}
+
+fn pending(message: &str) -> PendingMessage {
+    PendingMessage {
+        digest: HashFunction::from(HashFunctionCode::Blake3_256).derive(message.as_bytes()),
+        message: message.to_string(),
+        signatures: vec![Signature::NonTransferable(Nontransferable::Couplet(vec![]))],
+        signed_message: message.to_string(),
+        attempts: 0,
+    }
+}
+
+fn check_reverify(store: &mut (dyn ReverifyStore + 'static)) {
+    let queue = vec![pending("first"), pending("second")];
+    assert!(store.queue("Identifier").unwrap().is_empty());
+    store.save_queue("Identifier", &queue).unwrap();
+    store.save_queue("Identifier2", &queue[..1]).unwrap();
+    assert_eq!(store.queue("Identifier").unwrap(), queue);
+
+    store.save_queue("Identifier2", &[]).unwrap();
+    assert!(store.queue("Identifier2").unwrap().is_empty());
+    assert_eq!(store.keys().unwrap(), vec!["Identifier"]);
+}
+
+#[test]
+fn test_reverify_stores() {
+    check_backends::<dyn ReverifyStore>(
+        Box::new(InMemoryReverify::default()),
+        |db| Box::new(SledReverify::new(db).unwrap()),
+        check_reverify,
+        |store| {
+            // Waiting messages are kept after reopening.
+            assert_eq!(
+                store.queue("Identifier").unwrap(),
+                vec![pending("first"), pending("second")]
+            );
+            assert_eq!(store.keys().unwrap(), vec!["Identifier"]);
+        },
+    );
+}
+
+#[actix_web::test]
+async fn test_subscribe() {
+    let (_, storage, _) = ValidateHandle::in_memory(RetentionPolicy::default());
+    // Messages saved before subscribing aren't announced.
+    storage
+        .save("Identifier".into(), message("d0", "saved0"))
+        .await
+        .unwrap();
+
+    let mut saved = storage.subscribe();
+    for (key, digest) in [("Identifier", "d1"), ("Identifier2", "d2")] {
+        storage
+            .save(key.into(), message(digest, "saved"))
+            .await
+            .unwrap();
+    }
+    assert_eq!(saved.recv().await.unwrap(), "Identifier");
+    assert_eq!(saved.recv().await.unwrap(), "Identifier2");
+    assert!(saved.try_recv().is_err());
+}
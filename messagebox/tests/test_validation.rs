@@ -1,11 +1,25 @@
 use anyhow::Error;
 use messagebox::{
-    forward_message, messagebox::MessageBox, query_by_digest, query_by_sn, register_token,
+    forward_message,
+    messagebox::{MessageBox, MessageBoxOptions},
+    query_by_digest, query_by_sn, register_token,
+    storage::Stores,
 };
 use said::derivation::{HashFunction, HashFunctionCode};
+use serde_json::Value;
 use tempfile::Builder;
 use url::Url;
 
+/// Returns forwarded data of messages from query response.
+fn data(messages: &Value) -> Vec<&str> {
+    messages
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|msg| msg["a"].as_str().unwrap())
+        .collect()
+}
+
 #[actix_web::test]
 async fn test_validation() -> Result<(), Error> {
     let reg = register_token("Identifier".to_string(), "cEm86d15R7iiArf4J1VMi2:APA91bFozuXaqh6NxqhusEF-7B9RAeVfNbmwHWC4DjwwWMZEzRPcq2ctPQZobKRxSkQtjWp5O0VqktRLAubaNer6rsuzLPz-YaKDQJQlVz1Fp3OHL6UlMutElWzbykdNwI0fENxdFkb6".to_string());
@@ -24,7 +38,6 @@ async fn test_validation() -> Result<(), Error> {
     // Setup first identifier.
     let root = Builder::new().prefix("test-db").tempdir().unwrap();
     let root2 = Builder::new().prefix("test-db").tempdir().unwrap();
-    let server_key = "AAAAky1v068:APA91bHHpGtP6M5h3ICFc9AzY35MrkTmjwblkLlEJ1C0yvkrUu7KDkmkXMzPq2q-0o1l49fKxOeDQaKIkZTTEAIX3Jd45j6KNtSempYqop4Psitvz2Ng7iBz-IeS1SGEs1GpnWseJlpP".to_string();
     let watcher_oobi = serde_json::from_str(r#"{"eid":"BF2t2NPc1bwptY1hYV0YCib1JjQ11k9jtuaZemecPF5b","scheme":"http","url":"http://localhost:3236/"}"#).unwrap();
 
     let messagebox = MessageBox::setup(
@@ -33,47 +46,73 @@ async fn test_validation() -> Result<(), Error> {
         watcher_oobi,
         Url::parse("http:/blabla.com").unwrap(),
         None,
-        Some(server_key),
+        Stores::in_memory(),
+        MessageBoxOptions::default(),
     )
     .await
     .unwrap();
 
     messagebox
         .validator_handle
-        .validate(reg.to_string())
+        .validate("Identifier".to_string(), reg.to_string(), reg.to_string())
         .await?;
     messagebox
         .validator_handle
-        .validate(exchange.to_string())
+        .validate(
+            "Identifier".to_string(),
+            exchange.to_string(),
+            exchange.to_string(),
+        )
         .await?;
     messagebox
         .validator_handle
-        .validate(exchange1.to_string())
+        .validate(
+            "Identifier".to_string(),
+            exchange1.to_string(),
+            exchange1.to_string(),
+        )
         .await?;
     messagebox
         .validator_handle
-        .validate(exchange2.to_string())
+        .validate(
+            "Identifier".to_string(),
+            exchange2.to_string(),
+            exchange2.to_string(),
+        )
         .await?;
     let res = messagebox
         .validator_handle
-        .validate(query.to_string())
+        .validate(
+            "Identifier".to_string(),
+            query.to_string(),
+            query.to_string(),
+        )
         .await;
-    assert_eq!(
-        res?.unwrap(),
-        "{\"last_sn\":2,\"messages\":[\"saved0\",\"saved1\",\"saved2\"]}"
-    );
+    let res: Value = serde_json::from_str(&res?.unwrap())?;
+    assert_eq!(res["last_sn"], 2);
+    assert_eq!(data(&res["messages"]), vec!["saved0", "saved1", "saved2"]);
 
     let query = query_by_sn("Identifier".to_string(), 2);
     let res = messagebox
         .validator_handle
-        .validate(query.to_string())
+        .validate(
+            "Identifier".to_string(),
+            query.to_string(),
+            query.to_string(),
+        )
         .await;
-    assert_eq!(res?.unwrap(), "{\"last_sn\":2,\"messages\":[\"saved2\"]}");
+    let res: Value = serde_json::from_str(&res?.unwrap())?;
+    assert_eq!(res["last_sn"], 2);
+    assert_eq!(data(&res["messages"]), vec!["saved2"]);
 
     let query = query_by_sn("Identifier".to_string(), 4);
     let res = messagebox
         .validator_handle
-        .validate(query.to_string())
+        .validate(
+            "Identifier".to_string(),
+            query.to_string(),
+            query.to_string(),
+        )
         .await;
     assert_eq!(res?, None);
 
@@ -84,8 +123,12 @@ async fn test_validation() -> Result<(), Error> {
     let query_by_digest = serde_json::to_string(&qry).unwrap();
     dbg!(query_by_digest);
 
-    let res = messagebox.validator_handle.validate(qry.to_string()).await;
-    assert_eq!(res?, Some("[\"saved0\",\"saved1\"]".to_string()));
+    let res = messagebox
+        .validator_handle
+        .validate("Identifier".to_string(), qry.to_string(), qry.to_string())
+        .await;
+    let res: Value = serde_json::from_str(&res?.unwrap())?;
+    assert_eq!(data(&res), vec!["saved0", "saved1"]);
 
     Ok(())
 }